@@ -1,37 +1,119 @@
 use std::fmt;
 
-use crate::{gfx, piece, util, js_api};
+use crate::{gfx, piece, fumen, encoding, util};
+use crate::platform::{Platform};
 
+// Rows are numbered from the top of the visible area, so the hidden rows
+// above it (the vanish zone) have negative y. Internally, blocks are stored
+// by row index, which counts from the topmost hidden row.
 pub struct Board {
     width: usize,
     height: usize,
+    num_hidden_rows: usize,
     data: Vec<Option<gfx::Color>>,
+
+    // Time each block was placed at, for fading it out
+    placed_at: Vec<f64>,
+    time: f64,
+}
+
+// Blocks stay visible for `delay` milliseconds after being placed, then fade
+// out over `duration` milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fade {
+    pub delay: f64,
+    pub duration: f64,
 }
 
 impl Board {
-    pub fn new(width: usize, height: usize) -> Self {
+    pub fn new(width: usize, height: usize, num_hidden_rows: usize) -> Self {
+        let num_blocks = width * (num_hidden_rows + height);
+
         Self {
-            width: width,
-            height: height,
-            data: vec![None; width * height],
+            width,
+            height,
+            num_hidden_rows,
+            data: vec![None; num_blocks],
+
+            placed_at: vec![0.0; num_blocks],
+            time: 0.0,
+        }
+    }
+
+    pub fn encode(&self, writer: &mut encoding::Writer) {
+        writer.varint(self.width as u64);
+        writer.varint(self.height as u64);
+        writer.varint(self.num_hidden_rows as u64);
+        writer.f64(self.time);
+
+        for (block, &placed_at) in self.data.iter().zip(self.placed_at.iter()) {
+            match block {
+                Some(color) => {
+                    writer.varint(color.to_argb32() as u64 + 1);
+                    writer.f64(placed_at);
+                },
+                None => writer.varint(0),
+            }
+        }
+    }
+
+    pub fn decode(reader: &mut encoding::Reader) -> Option<Self> {
+        let width = reader.varint()? as usize;
+        let height = reader.varint()? as usize;
+        let num_hidden_rows = reader.varint()? as usize;
+
+        // Every block takes at least a byte
+        if width.checked_mul(height.checked_add(num_hidden_rows)?)? > reader.remaining() {
+            return None;
+        }
+
+        let mut obj = Self::new(width, height, num_hidden_rows);
+        obj.time = reader.f64()?;
+
+        for i in 0..obj.data.len() {
+            let value = reader.varint()?;
+            if value > 0 {
+                obj.data[i] = Some(gfx::Color::from_argb32((value - 1) as u32));
+                obj.placed_at[i] = reader.f64()?;
+            }
         }
+
+        Some(obj)
     }
 
-    fn get_block(&self, x: usize, y: usize) -> &Option<gfx::Color> {
-        &self.data[y * self.width + x]
+    fn num_rows(&self) -> usize {
+        self.num_hidden_rows + self.height
     }
 
-    fn put_block(&mut self, x: usize, y: usize, color: gfx::Color) {
-        self.data[y * self.width + x] = Some(color);
+    fn row_index(&self, y: i32) -> Option<usize> {
+        let row = y + self.num_hidden_rows as i32;
+        if row >= 0 && row < self.num_rows() as i32 {
+            Some(row as usize)
+        } else {
+            None
+        }
+    }
+
+    fn get_block(&self, x: usize, row: usize) -> &Option<gfx::Color> {
+        &self.data[row * self.width + x]
     }
 
-    fn is_line(&self, y: usize) -> bool {
-        (0..self.width).all(|x| { self.get_block(x, y).is_some() })
+    fn put_block(&mut self, x: usize, row: usize, color: gfx::Color) {
+        self.data[row * self.width + x] = Some(color);
+        self.placed_at[row * self.width + x] = self.time;
     }
 
-    fn clear_row(&mut self, y: usize) {
+    fn is_line(&self, row: usize) -> bool {
+        (0..self.width).all(|x| { self.get_block(x, row).is_some() })
+    }
+
+    fn empty_columns(&self, row: usize) -> Vec<usize> {
+        (0..self.width).filter(|&x| { self.get_block(x, row).is_none() }).collect()
+    }
+
+    fn clear_row(&mut self, row: usize) {
         for x in 0..self.width {
-            self.data[y * self.width + x] = None
+            self.data[row * self.width + x] = None
         }
     }
 
@@ -41,6 +123,9 @@ impl Board {
 
             let src = &top[(y_top * self.width)..(y_top * self.width + self.width)];
             bottom[..self.width].clone_from_slice(src);
+
+            let src = y_top * self.width;
+            self.placed_at.copy_within(src..(src + self.width), y_bottom * self.width);
         }
     }
 
@@ -48,33 +133,69 @@ impl Board {
         self.width
     }
 
+    // Number of visible rows
     pub fn height(&self) -> usize {
         self.height
     }
 
+    pub fn num_hidden_rows(&self) -> usize {
+        self.num_hidden_rows
+    }
+
     pub fn clear(&mut self) {
         self.data.clear();
-        self.data.resize(self.width * self.height, None);
+        self.data.resize(self.width * self.num_rows(), None);
+    }
+
+    // Sets the time that blocks placed from now on are stamped with
+    pub fn set_time(&mut self, time: f64) {
+        self.time = time;
     }
 
-    pub fn draw(&self) {
+    pub fn draw<P: Platform>(&self, platform: &mut P, fade: Option<Fade>) {
         for by in 0..self.height {
+            let row = by + self.num_hidden_rows;
+
             for bx in 0..self.width {
-                let color = self.get_block(bx, by)
+                let color = self.get_block(bx, row)
                     .as_ref()
-                    .map(|x| { x.to_argb32() })
+                    .map(|x| {
+                        match fade {
+                            Some(fade) => {
+                                let age = self.time - self.placed_at[row * self.width + bx];
+                                let t = util::clamp((age - fade.delay) / fade.duration, 0.0, 1.0);
+                                x.fade(1.0 - t).to_argb32()
+                            },
+                            None => x.to_argb32(),
+                        }
+                    })
                     .unwrap_or(0);
 
-                js_api::draw_block(bx as u32, by as u32, color)
+                platform.draw_block(bx as u32, by as u32, color)
             }
         }
     }
 
+    // Blocks in the visible area
+    pub fn iter_blocks(&self) -> impl Iterator<Item = (usize, usize, &gfx::Color)> + '_ {
+        self.data.iter()
+            .enumerate()
+            .skip(self.num_hidden_rows * self.width)
+            .filter_map(move |(i, block)| {
+                block.as_ref().map(|color| { (i % self.width, i / self.width - self.num_hidden_rows, color) })
+            })
+    }
+
+    // Pieces spawn in the two rows above the visible area, as far as there
+    // are hidden rows, i.e. in rows 21 and 22 of a standard board. Pieces
+    // that are a single row high spawn in the lower one.
     pub fn initial_position(&self, piece: &piece::Piece, rotation: usize) -> util::Position {
-        let ((x1, y1), (x2, _)) = piece.bounds(rotation);
+        let ((x1, y1), (x2, y2)) = piece.bounds(rotation);
+        let num_spawn_rows = self.num_hidden_rows.min(2) as i32;
+
         util::Position::new(
             (self.width / 2) as i32 - ((x1 + x2 + 1) / 2) as i32,
-            -(y1 as i32),
+            (-1 - y2 as i32).max(-num_spawn_rows - y1 as i32),
         )
     }
 
@@ -83,31 +204,32 @@ impl Board {
             let dx = position.x + (bx as i32);
             let dy = position.y + (by as i32);
 
-            if dx >= 0 && dx < self.width as i32 && dy >= 0 && dy < self.height as i32 {
-                self.put_block(dx as usize, dy as usize, piece.color.clone());
+            if let (true, Some(row)) = (dx >= 0 && dx < self.width as i32, self.row_index(dy)) {
+                self.put_block(dx as usize, row, piece.color.clone());
             }
         }
     }
 
-    pub fn collides(&self, piece: &piece::Piece, position: &util::Position, rotation: usize) -> bool {
-        for (bx, by) in piece.iter_coords(rotation) {
-            let dx = position.x + (bx as i32);
-            let dy = position.y + (by as i32);
-
-            if dx < 0 || dx >= self.width as i32 {
-                return true;
-            }
-
-            if dy < 0 || dy >= self.height as i32 {
-                return true;
-            }
+    pub fn is_blocked(&self, x: i32, y: i32) -> bool {
+        if x < 0 || x >= self.width as i32 {
+            return true;
+        }
 
-            if self.get_block(dx as usize, dy as usize).is_some() {
-                return true;
-            }
+        match self.row_index(y) {
+            Some(row) => self.get_block(x as usize, row).is_some(),
+            None => true,
         }
+    }
+
+    // Whether the piece lies entirely in the hidden rows
+    pub fn is_hidden(&self, piece: &piece::Piece, position: &util::Position, rotation: usize) -> bool {
+        piece.iter_coords(rotation).all(|(_, by)| { position.y + (by as i32) < 0 })
+    }
 
-        false
+    pub fn collides(&self, piece: &piece::Piece, position: &util::Position, rotation: usize) -> bool {
+        piece.iter_coords(rotation).any(|(bx, by)| {
+            self.is_blocked(position.x + (bx as i32), position.y + (by as i32))
+        })
     }
 
     pub fn find_drop_position(&self, piece: &piece::Piece, position: &util::Position, rotation: usize) -> util::Position {
@@ -121,15 +243,156 @@ impl Board {
             .unwrap()
     }
 
-    pub fn clear_lines(&mut self) -> Vec<usize> {
+    // Fumen rows from the top of the field, lined up with the board's bottom row
+    fn fumen_row_to_y(&self, row: i32) -> i32 {
+        row - fumen::HEIGHT as i32 + self.height as i32
+    }
+
+    // Encodes the board as fumen, which is 10 columns wide and 23 rows high,
+    // so whatever doesn't fit is cut off. Blocks are stored by the piece
+    // whose color they have, or as garbage.
+    pub fn to_fumen(&self, pieces: &[piece::Piece], active: Option<(&piece::Piece, &util::Position, usize)>, comment: Option<&str>) -> String {
+        let mut field = vec![0; fumen::NUM_BLOCKS];
+        for row in 0..fumen::HEIGHT {
+            for x in 0..self.width.min(fumen::WIDTH) {
+                let block = self.row_index(self.fumen_row_to_y(row as i32)).and_then(|index| { self.get_block(x, index).as_ref() });
+                if let Some(color) = block {
+                    field[row * fumen::WIDTH + x] = pieces.iter()
+                        .find(|piece| { piece.color.to_argb32() == color.to_argb32() })
+                        .and_then(|piece| { fumen::piece_value(&piece.name) })
+                        .unwrap_or(fumen::GARBAGE);
+                }
+            }
+        }
+
+        let piece = active.and_then(|(piece, position, rotation)| {
+            let offset = self.fumen_row_to_y(0);
+            let blocks: Vec<_> = piece.iter_coords(rotation)
+                .map(|(bx, by)| { (position.x + bx as i32, position.y + by as i32 - offset) })
+                .collect();
+
+            // Rotation systems don't agree on which orientation comes first
+            let value = fumen::piece_value(&piece.name)?;
+            (0..4).find_map(|i| { fumen::Piece::from_blocks(value, (rotation + i) % 4, &blocks) })
+        });
+
+        fumen::encode(&fumen::Page { field, piece, comment: comment.map(|x| { x.to_string() }) })
+    }
+
+    // Replaces all blocks with the field of a fumen page, colored like the
+    // pieces of the same name
+    pub fn load_fumen(&mut self, page: &fumen::Page, pieces: &[piece::Piece]) {
+        self.clear();
+
+        for row in 0..fumen::HEIGHT {
+            for x in 0..self.width.min(fumen::WIDTH) {
+                let value = page.field[row * fumen::WIDTH + x];
+                if let (true, Some(index)) = (value != 0, self.row_index(self.fumen_row_to_y(row as i32))) {
+                    let color = fumen::piece_name(value)
+                        .and_then(|name| { pieces.iter().find(|piece| { piece.name == name }) })
+                        .map(|piece| { piece.color.clone() })
+                        .unwrap_or_else(gfx::Color::gray);
+
+                    self.put_block(x, index, color);
+                }
+            }
+        }
+    }
+
+    // Index into `pieces`, position and rotation of a fumen page's piece, if
+    // the piece set has one of the same name and shape
+    pub fn fumen_piece_position(&self, fumen_piece: &fumen::Piece, pieces: &[piece::Piece]) -> Option<(usize, util::Position, usize)> {
+        let name = fumen::piece_name(fumen_piece.value)?;
+        let index = pieces.iter().position(|piece| { piece.name == name })?;
+        let piece = &pieces[index];
+
+        let mut blocks: Vec<_> = fumen_piece.blocks().iter()
+            .map(|&(x, row)| { (x, self.fumen_row_to_y(row)) })
+            .collect();
+        blocks.sort_unstable();
+
+        let bx = blocks.iter().map(|&(x, _)| { x }).min()?;
+        let by = blocks.iter().map(|&(_, y)| { y }).min()?;
+
+        // Line up the top left corners, then compare the shapes, starting with
+        // the same rotation as rotation systems don't agree on the first one
+        (0..4).map(|i| { (fumen_piece.rotation + i) % 4 }).find_map(|rotation| {
+            let ((x1, y1), _) = piece.bounds(rotation);
+            let position = util::Position::new(bx - x1 as i32, by - y1 as i32);
+
+            let mut piece_blocks: Vec<_> = piece.iter_coords(rotation)
+                .map(|(x, y)| { (position.x + x as i32, position.y + y as i32) })
+                .collect();
+            piece_blocks.sort_unstable();
+
+            if piece_blocks == blocks { Some((index, position, rotation)) } else { None }
+        })
+    }
+
+    // Pushes the stack up by one row and fills the new bottom row, except for
+    // the columns in `holes`. Returns false if blocks were pushed off the top.
+    pub fn insert_garbage_row(&mut self, holes: &[usize], color: &gfx::Color) -> bool {
+        // Row 0 is the topmost hidden row
+        let has_overflow = (0..self.width).any(|x| { self.get_block(x, 0).is_some() });
+
+        self.data.drain(..self.width);
+        self.data.extend((0..self.width).map(|x| {
+            if holes.contains(&x) { None } else { Some(color.clone()) }
+        }));
+
+        let time = self.time;
+        self.placed_at.drain(..self.width);
+        self.placed_at.extend((0..self.width).map(|_| { time }));
+
+        !has_overflow
+    }
+
+    // Inserts `num_rows` garbage rows with `num_holes` holes each. The holes
+    // of a row line up with those of the row above it, unless they are moved
+    // to random columns, which happens with a probability of `messiness`.
+    pub fn insert_garbage(&mut self, num_rows: usize, num_holes: usize, messiness: f64, rng: &mut util::Pcg32) -> bool {
+        let num_holes = util::clamp(num_holes, 1, self.width - 1);
+
+        let mut holes = self.empty_columns(self.num_rows() - 1);
+        let mut is_ok = true;
+
+        for _ in 0..num_rows {
+            if holes.len() != num_holes || rng.next_f64() < messiness {
+                let mut columns: Vec<usize> = (0..self.width).collect();
+                for i in 0..num_holes {
+                    let j = i + rng.next_below((self.width - i) as u32) as usize;
+                    columns.swap(i, j);
+                }
+
+                holes = columns[..num_holes].to_vec();
+            }
+
+            is_ok &= self.insert_garbage_row(&holes, &gfx::Color::gray());
+        }
+
+        is_ok
+    }
+
+    // Empties the hidden rows and the `num_rows` topmost visible rows, leaving
+    // the rest of the stack in place. Returns the cleared visible rows.
+    pub fn clear_top_rows(&mut self, num_rows: usize) -> Vec<usize> {
+        for row in 0..(self.num_hidden_rows + num_rows.min(self.height)) {
+            self.clear_row(row);
+        }
+
+        (0..num_rows.min(self.height)).collect()
+    }
+
+    // Returns the cleared rows, which are negative for hidden ones
+    pub fn clear_lines(&mut self) -> Vec<i32> {
         let mut cleared_lines = Vec::new();
 
-        let mut y1_iter = (0..self.height).rev();
-        let mut y2_iter = (0..self.height).rev().peekable();
+        let mut y1_iter = (0..self.num_rows()).rev();
+        let mut y2_iter = (0..self.num_rows()).rev().peekable();
 
         while let (Some(y1), Some(&y2)) = (y1_iter.next(), y2_iter.peek()) {
             if self.is_line(y1) {
-                cleared_lines.push(y1);
+                cleared_lines.push(y1 as i32 - self.num_hidden_rows as i32);
             } else {
                 y2_iter.next();
                 self.clone_row(y1, y2);
@@ -146,12 +409,121 @@ impl Board {
 
 impl fmt::Debug for Board {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for y in 0..self.height {
+        for row in 0..self.num_rows() {
             for x in 0..self.width {
-                write!(f, "{}", if self.get_block(x, y).is_some() { "X" } else { " " })?;
+                write!(f, "{}", if self.get_block(x, row).is_some() { "X" } else { " " })?;
             }
             write!(f, "\n")?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Board with every row filled, except for the given column
+    fn filled(width: usize, height: usize, num_hidden_rows: usize, hole: usize) -> Board {
+        let mut board = Board::new(width, height, num_hidden_rows);
+        for _ in 0..(height + num_hidden_rows) {
+            board.insert_garbage_row(&[hole], &gfx::Color::gray());
+        }
+
+        board
+    }
+
+    #[test]
+    fn clear_top_rows() {
+        let mut board = filled(4, 6, 2, 0);
+        assert_eq!(board.clear_top_rows(3), vec![0, 1, 2]);
+        assert!((-2..3).all(|y| { !board.is_blocked(1, y) }));
+        assert!((3..6).all(|y| { board.is_blocked(1, y) && !board.is_blocked(0, y) }));
+
+        // Without hidden rows, and with more rows than the board has
+        let mut board = filled(4, 6, 0, 0);
+        assert_eq!(board.clear_top_rows(10), (0..6).collect::<Vec<_>>());
+        assert_eq!(board.iter_blocks().count(), 0);
+    }
+
+    fn holes(board: &Board, y: i32) -> Vec<usize> {
+        (0..board.width()).filter(|&x| { !board.is_blocked(x as i32, y) }).collect()
+    }
+
+    #[test]
+    fn insert_garbage_row() {
+        let mut board = Board::new(5, 3, 1);
+        for _ in 0..4 {
+            assert!(board.insert_garbage_row(&[1, 3], &gfx::Color::gray()));
+        }
+
+        assert!((-1..3).all(|y| { holes(&board, y) == vec![1, 3] }));
+
+        // The next row pushes blocks off the top
+        assert!(!board.insert_garbage_row(&[0], &gfx::Color::gray()));
+        assert_eq!(holes(&board, 2), vec![0]);
+        assert_eq!(holes(&board, -1), vec![1, 3]);
+    }
+
+    #[test]
+    fn insert_garbage() {
+        let mut rng = util::Pcg32::new(1);
+
+        // Tidy garbage keeps its holes lined up, and there is always at least
+        // one hole per row and at least one block
+        let mut board = Board::new(5, 4, 1);
+        assert!(board.insert_garbage(3, 0, 0.0, &mut rng));
+        let bottom_holes = holes(&board, 3);
+        assert_eq!(bottom_holes.len(), 1);
+        assert!((1..3).all(|y| { holes(&board, y) == bottom_holes }));
+        assert_eq!(holes(&board, 0).len(), 5);
+
+        let mut board = Board::new(5, 4, 1);
+        assert!(board.insert_garbage(5, 10, 1.0, &mut rng));
+        assert!((-1..4).all(|y| { holes(&board, y).len() == 4 }));
+        assert!(!board.insert_garbage(1, 1, 0.0, &mut rng));
+    }
+
+    #[test]
+    fn clear_lines() {
+        // Full rows in the hidden rows and at the bottom
+        let mut board = Board::new(4, 4, 2);
+        for holes in &[vec![0], vec![], vec![1], vec![1], vec![1], vec![]] {
+            board.insert_garbage_row(holes, &gfx::Color::gray());
+        }
+
+        assert_eq!(board.clear_lines(), vec![3, -1]);
+        assert!((1..4).all(|y| { holes(&board, y) == vec![1] }));
+        assert_eq!(holes(&board, 0), vec![0]);
+        assert!((-2..0).all(|y| { holes(&board, y).len() == 4 }));
+        assert_eq!(board.clear_lines(), vec![]);
+    }
+
+    #[test]
+    fn initial_position() {
+        let pieces = piece::make_standard();
+        let find = |name| { pieces.iter().find(|x| { x.name == name }).unwrap() };
+        let (i, o, t) = (find("I"), find("O"), find("T"));
+
+        // Pieces spawn in the two rows above the visible area, or the lower
+        // one if they are a single row high
+        let board = Board::new(10, 20, 2);
+        assert_eq!(board.initial_position(i, 0), util::Position::new(3, -2));
+        assert_eq!(board.initial_position(o, 0), util::Position::new(4, -2));
+        assert_eq!(board.initial_position(t, 0), util::Position::new(4, -2));
+        assert!(board.is_hidden(i, &board.initial_position(i, 0), 0));
+        assert!(board.is_hidden(t, &board.initial_position(t, 0), 0));
+        assert!(!board.is_hidden(t, &util::Position::new(4, -1), 0));
+
+        // Otherwise as far up as there is room
+        let board = Board::new(10, 20, 1);
+        assert_eq!(board.initial_position(i, 0), util::Position::new(3, -2));
+        assert_eq!(board.initial_position(t, 0), util::Position::new(4, -1));
+        assert!(!board.is_hidden(t, &board.initial_position(t, 0), 0));
+
+        let board = Board::new(10, 20, 0);
+        assert_eq!(board.initial_position(i, 0), util::Position::new(3, -1));
+        assert_eq!(board.initial_position(t, 0), util::Position::new(4, 0));
+    }
+}
+
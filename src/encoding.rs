@@ -0,0 +1,308 @@
+// Minimal binary encoding helpers: little-endian integers and floats,
+// LEB128 varints with zigzag encoding for signed values, and a checksum.
+
+#[derive(Default)]
+pub struct Writer {
+    bytes: Vec<u8>,
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn raw(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.raw(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.raw(&value.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.u64(value.to_bits());
+    }
+
+    pub fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.u8((value as u8 & 0x7f) | 0x80);
+            value >>= 7;
+        }
+
+        self.u8(value as u8);
+    }
+
+    pub fn signed(&mut self, value: i64) {
+        self.varint(zigzag(value));
+    }
+
+    // Prefixed with their length
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.varint(value.len() as u64);
+        self.raw(value);
+    }
+
+    // UTF-8, prefixed with its length in bytes
+    pub fn string(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+
+    pub fn optional_varint(&mut self, value: Option<u64>) {
+        self.varint(value.map(|x| { x + 1 }).unwrap_or(0));
+    }
+
+    pub fn optional_f64(&mut self, value: Option<f64>) {
+        match value {
+            Some(value) => { self.u8(1); self.f64(value) },
+            None => self.u8(0),
+        }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    // Appends the checksum of everything written so far
+    pub fn into_checksummed_bytes(mut self) -> Vec<u8> {
+        let checksum = checksum(&self.bytes);
+        self.u32(checksum);
+
+        self.bytes
+    }
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, index: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.index
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remaining() == 0
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len().saturating_sub(self.index)
+    }
+
+    pub fn raw(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.index..self.index.checked_add(len)?)?;
+        self.index += len;
+
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.raw(1).map(|x| { x[0] })
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.raw(4)?);
+
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.raw(8)?);
+
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    pub fn f64(&mut self) -> Option<f64> {
+        self.u64().map(f64::from_bits)
+    }
+
+    pub fn varint(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+
+        None
+    }
+
+    pub fn signed(&mut self) -> Option<i64> {
+        self.varint().map(unzigzag)
+    }
+
+    pub fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.varint()?;
+        if len > self.remaining() as u64 {
+            return None;
+        }
+
+        self.raw(len as usize)
+    }
+
+    pub fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    pub fn optional_varint(&mut self) -> Option<Option<u64>> {
+        self.varint().map(|x| { x.checked_sub(1) })
+    }
+
+    pub fn optional_f64(&mut self) -> Option<Option<f64>> {
+        match self.u8()? {
+            0 => Some(None),
+            _ => self.f64().map(Some),
+        }
+    }
+}
+
+// Strips the checksum appended by `Writer::into_checksummed_bytes`, if it matches
+pub fn verify_checksum(bytes: &[u8]) -> Option<&[u8]> {
+    if bytes.len() < 4 {
+        return None;
+    }
+
+    let (data, checksum_bytes) = bytes.split_at(bytes.len() - 4);
+    if Reader::new(checksum_bytes).u32()? == checksum(data) {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+// Maps signed to unsigned values so that small magnitudes stay small:
+// 0, -1, 1, -2, 2 ... become 0, 1, 2, 3, 4 ...
+pub fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+// 32-bit FNV-1a, see http://www.isthe.com/chongo/tech/comp/fnv/
+pub fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut writer = Writer::new();
+        writer.u8(0xab);
+        writer.u32(0xdead_beef);
+        writer.u64(u64::MAX);
+        writer.f64(-1234.5);
+        writer.varint(0);
+        writer.varint(300);
+        writer.varint(u64::MAX);
+        writer.signed(-1);
+        writer.signed(i64::MIN);
+        writer.bytes(&[1, 2, 3]);
+        writer.string("Tetris™");
+        writer.optional_varint(None);
+        writer.optional_varint(Some(0));
+        writer.optional_f64(None);
+        writer.optional_f64(Some(0.25));
+        let bytes = writer.into_bytes();
+
+        let mut reader = Reader::new(&bytes);
+        assert_eq!(reader.u8(), Some(0xab));
+        assert_eq!(reader.u32(), Some(0xdead_beef));
+        assert_eq!(reader.u64(), Some(u64::MAX));
+        assert_eq!(reader.f64(), Some(-1234.5));
+        assert_eq!(reader.varint(), Some(0));
+        assert_eq!(reader.varint(), Some(300));
+        assert_eq!(reader.varint(), Some(u64::MAX));
+        assert_eq!(reader.signed(), Some(-1));
+        assert_eq!(reader.signed(), Some(i64::MIN));
+        assert_eq!(reader.bytes(), Some(&[1, 2, 3][..]));
+        assert_eq!(reader.string().as_deref(), Some("Tetris™"));
+        assert_eq!(reader.optional_varint(), Some(None));
+        assert_eq!(reader.optional_varint(), Some(Some(0)));
+        assert_eq!(reader.optional_f64(), Some(None));
+        assert_eq!(reader.optional_f64(), Some(Some(0.25)));
+        assert!(reader.is_empty());
+        assert_eq!(reader.u8(), None);
+    }
+
+    #[test]
+    fn varint_sizes() {
+        for &(value, len) in &[(0, 1), (127, 1), (128, 2), (16383, 2), (16384, 3), (u64::MAX, 10)] {
+            let mut writer = Writer::new();
+            writer.varint(value);
+            assert_eq!(writer.into_bytes().len(), len, "{}", value);
+        }
+    }
+
+    #[test]
+    fn zigzag_order() {
+        for (value, expected) in [0, -1, 1, -2, 2].iter().zip(0..) {
+            assert_eq!(zigzag(*value), expected);
+            assert_eq!(unzigzag(expected), *value);
+        }
+
+        for &value in &[i64::MIN, i64::MAX, -1000, 1000] {
+            assert_eq!(unzigzag(zigzag(value)), value);
+        }
+    }
+
+    #[test]
+    fn truncated_input() {
+        let mut writer = Writer::new();
+        writer.varint(300);
+        writer.string("text");
+        let bytes = writer.into_bytes();
+
+        assert_eq!(Reader::new(&bytes[..1]).varint(), None);
+        assert_eq!(Reader::new(&[0xff; 10]).varint(), None);
+        assert_eq!(Reader::new(&[1, 2, 3]).u32(), None);
+
+        let mut reader = Reader::new(&bytes[..bytes.len() - 1]);
+        assert_eq!(reader.varint(), Some(300));
+        assert_eq!(reader.string(), None);
+
+        // Lengths beyond the end of the input are rejected without allocating
+        assert_eq!(Reader::new(&[0xff, 0xff, 0xff, 0xff, 0x0f]).bytes(), None);
+        assert_eq!(Reader::new(&[2, 0xc3, 0x28]).string(), None);
+    }
+
+    #[test]
+    fn checksum_verification() {
+        // Test vectors of 32-bit FNV-1a
+        assert_eq!(checksum(b""), 0x811c_9dc5);
+        assert_eq!(checksum(b"a"), 0xe40c_292c);
+        assert_eq!(checksum(b"foobar"), 0xbf9c_f968);
+
+        let mut writer = Writer::new();
+        writer.string("payload");
+        let mut bytes = writer.into_checksummed_bytes();
+        assert_eq!(verify_checksum(&bytes), Some(&bytes[..bytes.len() - 4]));
+
+        bytes[2] ^= 1;
+        assert_eq!(verify_checksum(&bytes), None);
+        assert_eq!(verify_checksum(&[1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,427 @@
+// Fumen v115, the text encoding of board positions used by the fumen editor
+// (https://fumen.zui.jp/) and the setups shared on community wikis. Only the
+// first page of a fumen is read, and a single page is written.
+//
+// See https://github.com/knewjade/tetris-fumen for a reference implementation.
+//
+// The data following the "v115@" prefix is a sequence of base 64 digits,
+// least significant first, with a '?' after the first 42 and then every 47
+// digits that is ignored. A page consists of:
+//
+//     field      runs of blocks, from the top left, each as 2 digits:
+//                (value difference to the previous page + 8) * 240 + length - 1;
+//                an unchanged field is followed by 1 digit, the number of
+//                further unchanged pages
+//     piece      3 digits: value + 8 * rotation + 32 * position + 7680 * flags,
+//                with the flags for a rising garbage row (1), mirroring (2),
+//                guideline colors (4), a comment (8) and not locking (16)
+//     comment    2 digits length, then 5 digits for every 4 characters of
+//                the comment as escaped by JavaScript's `escape`
+
+pub const WIDTH: usize = 10;
+
+// Rows of the field, without the garbage row below them
+pub const HEIGHT: usize = 23;
+
+pub const NUM_BLOCKS: usize = WIDTH * (HEIGHT + 1);
+
+// Block and piece values, 0 is empty
+pub const GARBAGE: u8 = 8;
+const PIECE_NAMES: [&str; 7] = ["I", "L", "O", "Z", "T", "J", "S"];
+
+const DIGITS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const COMMENT_CHARS: &[u8; 95] = b" !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~";
+const MAX_COMMENT_LENGTH: usize = 4095;
+
+const FLAG_COLORIZE: u32 = 4;
+const FLAG_COMMENT: u32 = 8;
+
+#[derive(Debug, PartialEq)]
+pub struct Page {
+    // Block values, top row first, followed by the garbage row
+    pub field: Vec<u8>,
+    pub piece: Option<Piece>,
+    pub comment: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Piece {
+    pub value: u8,
+
+    // Clockwise quarter turns from the spawn orientation
+    pub rotation: usize,
+
+    // Rotation center as defined by SRS, with y counting up from the bottom row
+    pub x: i32,
+    pub y: i32,
+}
+
+pub fn piece_value(name: &str) -> Option<u8> {
+    PIECE_NAMES.iter().position(|&x| { x == name }).map(|x| { x as u8 + 1 })
+}
+
+pub fn piece_name(value: u8) -> Option<&'static str> {
+    PIECE_NAMES.get((value as usize).checked_sub(1)?).cloned()
+}
+
+impl Piece {
+    // Blocks relative to the rotation center in spawn orientation, y up
+    fn spawn_offsets(value: u8) -> [(i32, i32); 4] {
+        match value {
+            1 => [(-1, 0), (0, 0), (1, 0), (2, 0)],
+            2 => [(-1, 0), (0, 0), (1, 0), (1, 1)],
+            3 => [(0, 0), (1, 0), (0, 1), (1, 1)],
+            4 => [(-1, 1), (0, 1), (0, 0), (1, 0)],
+            5 => [(-1, 0), (0, 0), (1, 0), (0, 1)],
+            6 => [(-1, 0), (0, 0), (1, 0), (-1, 1)],
+            _ => [(-1, 0), (0, 0), (0, 1), (1, 1)],
+        }
+    }
+
+    // The stored position is a particular block of the piece rather than its
+    // rotation center for some pieces and rotations
+    fn center_offset(value: u8, rotation: usize) -> (i32, i32) {
+        match (piece_name(value), rotation) {
+            (Some("O"), 0) => (0, -1),
+            (Some("O"), 2) => (1, 0),
+            (Some("O"), 3) => (1, -1),
+            (Some("I"), 2) => (1, 0),
+            (Some("I"), 3) => (0, -1),
+            (Some("S"), 0) => (0, -1),
+            (Some("S"), 1) => (-1, 0),
+            (Some("Z"), 0) => (0, -1),
+            (Some("Z"), 3) => (1, 0),
+            _ => (0, 0),
+        }
+    }
+
+    // Blocks as (column, row from the top of the field)
+    pub fn blocks(&self) -> Vec<(i32, i32)> {
+        Self::spawn_offsets(self.value).iter()
+            .map(|&(dx, dy)| {
+                let (dx, dy) = match self.rotation % 4 {
+                    0 => (dx, dy),
+                    1 => (dy, -dx),
+                    2 => (-dx, -dy),
+                    _ => (-dy, dx),
+                };
+
+                (self.x + dx, HEIGHT as i32 - 1 - (self.y + dy))
+            })
+            .collect()
+    }
+
+    // The piece with the given value and rotation occupying `blocks`, if it fits
+    pub fn from_blocks(value: u8, rotation: usize, blocks: &[(i32, i32)]) -> Option<Self> {
+        piece_name(value)?;
+
+        let min = |blocks: &[(i32, i32)]| {
+            blocks.iter().fold((i32::MAX, i32::MAX), |(x1, y1), &(x, y)| { (x1.min(x), y1.min(y)) })
+        };
+
+        // Line up the top left corners, keeping in mind that y counts up
+        let origin = Self { value, rotation: rotation % 4, x: 0, y: 0 };
+        let ((ox, oy), (bx, by)) = (min(&origin.blocks()), min(blocks));
+        let piece = Self { x: bx - ox, y: oy - by, ..origin };
+
+        let mut piece_blocks = piece.blocks();
+        let mut blocks = blocks.to_vec();
+        piece_blocks.sort_unstable();
+        blocks.sort_unstable();
+
+        let is_inside = blocks.iter().all(|&(x, y)| { x >= 0 && x < WIDTH as i32 && y >= 0 && y < HEIGHT as i32 });
+        if piece_blocks == blocks && is_inside {
+            Some(piece)
+        } else {
+            None
+        }
+    }
+
+    fn encode_position(&self) -> u32 {
+        let (dx, dy) = Self::center_offset(self.value, self.rotation);
+        let (x, y) = (self.x - dx, self.y - dy);
+
+        ((HEIGHT as i32 - 1 - y) * WIDTH as i32 + x) as u32
+    }
+
+    fn decode_position(value: u8, rotation: usize, position: u32) -> Self {
+        let (dx, dy) = Self::center_offset(value, rotation);
+        let x = (position as usize % WIDTH) as i32;
+        let y = HEIGHT as i32 - 1 - (position as usize / WIDTH) as i32;
+
+        Self { value, rotation, x: x + dx, y: y + dy }
+    }
+}
+
+// Fumen numbers rotations starting from the reverse orientation
+fn convert_rotation(rotation: usize) -> usize {
+    [2, 1, 0, 3][rotation % 4]
+}
+
+fn push_digits(digits: &mut Vec<u8>, mut value: u32, num_digits: usize) {
+    for _ in 0..num_digits {
+        digits.push(DIGITS[(value % 64) as usize]);
+        value /= 64;
+    }
+}
+
+struct Reader {
+    digits: Vec<u32>,
+    index: usize,
+}
+
+impl Reader {
+    fn poll(&mut self, num_digits: usize) -> Result<u32, String> {
+        let digits = self.digits.get(self.index..self.index + num_digits).ok_or("Unexpected end of data")?;
+        self.index += num_digits;
+
+        Ok(digits.iter().rev().fold(0, |value, &digit| { value * 64 + digit }))
+    }
+}
+
+pub fn encode(page: &Page) -> String {
+    let mut digits = Vec::new();
+
+    // Differences to the empty field
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for &block in page.field.iter().chain(std::iter::repeat(&0)).take(NUM_BLOCKS) {
+        let diff = block as u32 + 8;
+        match runs.last_mut() {
+            Some((last_diff, count)) if *last_diff == diff => *count += 1,
+            _ => runs.push((diff, 1)),
+        }
+    }
+
+    for &(diff, count) in runs.iter() {
+        push_digits(&mut digits, diff * NUM_BLOCKS as u32 + count - 1, 2);
+    }
+
+    if runs == [(8, NUM_BLOCKS as u32)] {
+        push_digits(&mut digits, 0, 1);
+    }
+
+    let comment = page.comment.as_ref().map(|x| { escape_comment(x) });
+
+    let mut flags = FLAG_COLORIZE;
+    if comment.is_some() {
+        flags |= FLAG_COMMENT;
+    }
+
+    let (value, rotation, position) = match page.piece {
+        Some(piece) => (piece.value as u32, convert_rotation(piece.rotation) as u32, piece.encode_position()),
+        None => (0, 0, 0),
+    };
+
+    push_digits(&mut digits, value + 8 * rotation + 32 * position + 7680 * flags, 3);
+
+    if let Some(comment) = comment {
+        push_digits(&mut digits, comment.len() as u32, 2);
+        for chunk in comment.as_bytes().chunks(4) {
+            let value = chunk.iter().rev().fold(0, |value, &c| {
+                let index = COMMENT_CHARS.iter().position(|&x| { x == c }).unwrap_or(0);
+                value * (COMMENT_CHARS.len() as u32 + 1) + index as u32
+            });
+
+            push_digits(&mut digits, value, 5);
+        }
+    }
+
+    let mut text = "v115@".to_string();
+    for (i, &digit) in digits.iter().enumerate() {
+        if i >= 42 && (i - 42) % 47 == 0 {
+            text.push('?');
+        }
+        text.push(digit as char);
+    }
+
+    text
+}
+
+// Accepts a bare fumen or a link to one
+pub fn decode(text: &str) -> Result<Page, String> {
+    let start = ["v115@", "V115@", "m115@", "M115@", "d115@", "D115@"].iter()
+        .filter_map(|prefix| { text.find(prefix).map(|index| { index + prefix.len() }) })
+        .min()
+        .ok_or("Not a fumen v115 string")?;
+
+    let digits = text[start..].trim_end().bytes()
+        .filter(|&c| { c != b'?' })
+        .map(|c| {
+            DIGITS.iter()
+                .position(|&x| { x == c })
+                .map(|x| { x as u32 })
+                .ok_or_else(|| { format!("Invalid character '{}'", c as char) })
+        })
+        .collect::<Result<Vec<u32>, String>>()?;
+
+    let mut reader = Reader { digits, index: 0 };
+
+    let mut field = Vec::with_capacity(NUM_BLOCKS);
+    while field.len() < NUM_BLOCKS {
+        let value = reader.poll(2)?;
+        let (diff, count) = (value / NUM_BLOCKS as u32, (value % NUM_BLOCKS as u32 + 1) as usize);
+        if !(8..=8 + GARBAGE as u32).contains(&diff) || field.len() + count > NUM_BLOCKS {
+            return Err("Invalid field".to_string());
+        }
+
+        if diff == 8 && count == NUM_BLOCKS {
+            reader.poll(1)?;
+        }
+
+        field.resize(field.len() + count, (diff - 8) as u8);
+    }
+
+    let value = reader.poll(3)?;
+    let flags = value / 7680;
+
+    let piece_value = (value % 8) as u8;
+    let rotation = convert_rotation((value / 8 % 4) as usize);
+    let piece = match piece_value {
+        1..=7 => Some(Piece::decode_position(piece_value, rotation, value / 32 % NUM_BLOCKS as u32)),
+        _ => None,
+    };
+
+    let mut comment = None;
+    if flags & FLAG_COMMENT != 0 {
+        let len = reader.poll(2)? as usize;
+        let mut escaped = String::with_capacity(len);
+
+        while escaped.len() < len {
+            let mut value = reader.poll(5)?;
+            for _ in 0..4 {
+                let index = (value % (COMMENT_CHARS.len() as u32 + 1)) as usize;
+                escaped.push(*COMMENT_CHARS.get(index).ok_or("Invalid comment")? as char);
+                value /= COMMENT_CHARS.len() as u32 + 1;
+            }
+        }
+
+        escaped.truncate(len);
+        comment = Some(unescape_comment(&escaped));
+    }
+
+    Ok(Page { field, piece, comment })
+}
+
+// Same as JavaScript's `escape`, cut off at the maximum length
+fn escape_comment(comment: &str) -> String {
+    let mut escaped = String::new();
+    for unit in comment.encode_utf16() {
+        let part = match unit {
+            0x30..=0x39 | 0x41..=0x5a | 0x61..=0x7a => (unit as u8 as char).to_string(),
+            _ if unit < 0x80 && b"@*_+-./".contains(&(unit as u8)) => (unit as u8 as char).to_string(),
+            0..=0xff => format!("%{:02X}", unit),
+            _ => format!("%u{:04X}", unit),
+        };
+
+        if escaped.len() + part.len() > MAX_COMMENT_LENGTH {
+            break;
+        }
+        escaped.push_str(&part);
+    }
+
+    escaped
+}
+
+fn unescape_comment(escaped: &str) -> String {
+    let bytes = escaped.as_bytes();
+    let hex = |range: std::ops::Range<usize>| {
+        bytes.get(range)
+            .and_then(|x| { std::str::from_utf8(x).ok() })
+            .and_then(|x| { u16::from_str_radix(x, 16).ok() })
+    };
+
+    let mut units = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let unit = match (bytes[i], bytes.get(i + 1)) {
+            (b'%', Some(b'u')) => hex(i + 2..i + 6).map(|x| { (x, 6) }),
+            (b'%', _) => hex(i + 1..i + 3).map(|x| { (x, 3) }),
+            _ => None,
+        };
+
+        let (unit, len) = unit.unwrap_or((bytes[i] as u16, 1));
+        units.push(unit);
+        i += len;
+    }
+
+    String::from_utf16_lossy(&units)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_field() {
+        let page = decode("v115@vhAAgH").unwrap();
+        assert_eq!(page, Page { field: vec![0; NUM_BLOCKS], piece: None, comment: None });
+        assert_eq!(encode(&page), "v115@vhAAgH");
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in &["v115@9gilGeglRpGeRpbeAgH", "v115@vhAAgH"] {
+            assert_eq!(&encode(&decode(text).unwrap()), text);
+        }
+
+        let mut field = vec![0; NUM_BLOCKS];
+        for (i, block) in field.iter_mut().enumerate().skip(WIDTH * (HEIGHT - 4)) {
+            *block = (i % 9) as u8;
+        }
+
+        for value in 1..=7 {
+            for rotation in 0..4 {
+                let page = Page {
+                    field: field.clone(),
+                    piece: Some(Piece { value, rotation, x: 4, y: 10 }),
+                    comment: Some(format!("{} {}", piece_name(value).unwrap(), rotation)),
+                };
+
+                assert_eq!(decode(&encode(&page)), Ok(page));
+            }
+        }
+    }
+
+    #[test]
+    fn comments() {
+        let page = |comment: &str| { Page { field: vec![0; NUM_BLOCKS], piece: None, comment: Some(comment.to_string()) } };
+
+        for comment in &["", "Perfect clear!", "100% \"quoted\" ~ 50/50", "Ünïcödé 🎮", "x".repeat(200).as_str()] {
+            assert_eq!(decode(&encode(&page(comment))), Ok(page(comment)));
+        }
+
+        // Cut off at the maximum length rather than producing garbage
+        let comment = decode(&encode(&page(&"x".repeat(MAX_COMMENT_LENGTH + 10)))).unwrap().comment.unwrap();
+        assert_eq!(comment.len(), MAX_COMMENT_LENGTH);
+    }
+
+    #[test]
+    fn piece_blocks() {
+        // T in spawn orientation, pointing up, with its center in the bottom row
+        let piece = Piece { value: piece_value("T").unwrap(), rotation: 0, x: 4, y: 0 };
+        let bottom = HEIGHT as i32 - 1;
+        assert_eq!(piece.blocks(), vec![(3, bottom), (4, bottom), (5, bottom), (4, bottom - 1)]);
+
+        for value in 1..=7 {
+            for rotation in 0..4 {
+                let piece = Piece { value, rotation, x: 4, y: 5 };
+                assert_eq!(Piece::from_blocks(value, rotation, &piece.blocks()), Some(piece));
+            }
+        }
+
+        assert_eq!(Piece::from_blocks(piece.value, 0, &[(0, 0), (1, 0), (2, 0), (3, 0)]), None);
+        assert_eq!(Piece::from_blocks(piece.value, 0, &[(-1, 0), (0, 0), (1, 0), (0, -1)]), None);
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert!(decode("").is_err());
+        assert!(decode("v114@vhAAgH").is_err());
+        assert!(decode("v115@vh").is_err());
+        assert!(decode("v115@vh!AgH").is_err());
+        assert!(decode("v115@////").is_err());
+
+        // Links and trailing whitespace are fine
+        assert!(decode("https://fumen.zui.jp/?v115@vhAAgH\n").is_ok());
+    }
+}
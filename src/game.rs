@@ -1,4 +1,5 @@
-use crate::{input, gfx, piece, board, util, wasm_glue, js_api};
+use crate::{input, gfx, piece, randomizer, board, fumen, rotation, scoring, mode, speed, replay, encoding, util, wasm_glue, platform};
+use crate::platform::{Platform};
 
 const BLOCK_SIZE_PX: i32 = 50;
 
@@ -14,6 +15,14 @@ const INPUT_MOVE_RIGHT: (usize, usize)= (0, 68);
 const INPUT_ROTATE_CW: (usize, usize)= (0, 39);
 const INPUT_ROTATE_CCW: (usize, usize) = (0, 37);
 
+const INPUT_HOLD: (usize, usize) = (0, 38);
+const INPUT_PAUSE: (usize, usize) = (0, 80);
+
+const INPUT_MENU_PREV_MODE: (usize, usize) = (0, 37);
+const INPUT_MENU_NEXT_MODE: (usize, usize) = (0, 39);
+const INPUT_MENU_PREV_OPTION: (usize, usize) = (0, 40);
+const INPUT_MENU_NEXT_OPTION: (usize, usize) = (0, 38);
+
 const INITIAL_DELAY_SOFT_DROP: f64 = 1000.0 / 60.0 * 12.0;
 const REPEAT_DELAY_SOFT_DROP: f64 = 1000.0 / 60.0 * 3.0;
 
@@ -23,13 +32,43 @@ const REPEAT_DELAY_MOVE: f64 = 1000.0 / 60.0 * 3.0;
 const TOUCH_SWIPE_DISTANCE_THRESHOLD: f64 = BLOCK_SIZE_PX as f64 * 2.0;
 const TOUCH_TAP_DISTANCE_THRESHOLD: f64 = BLOCK_SIZE_PX as f64 / 2.0;
 const TOUCH_TAP_PERIOD_THRESHOLD: f64 = 500.0;
+const TOUCH_LONG_PRESS_PERIOD_THRESHOLD: f64 = 500.0;
 
 const ANIMATION_DURATION_HARD_DROP: f64 = 200.0;
-const ANIMATION_DURATION_LINE_CLEAR: f64 = 1000.0;
 const ANIMATION_DURATION_GAME_OVER: f64 = 3000.0;
+const ANIMATION_DURATION_REVEAL: f64 = 1500.0;
+
+// How long the revealed stack stays up before the game over animation
+const REVEAL_DURATION: f64 = 2000.0;
+
+const ACTION_TEXT_DURATION: f64 = 2000.0;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"RSTS";
+const SNAPSHOT_VERSION: u8 = 4;
+
+// Board sizes accepted from saved games and replays
+const MIN_BOARD_SIZE: usize = 4;
+const MAX_BOARD_SIZE: usize = 100;
+
+trait State<P: Platform> {
+    fn tick(&mut self, timestamp: f64, controller: &Controller, settings: &mut Settings, platform: &mut P) -> Option<Box<dyn State<P>>>;
+
+    fn pause(&mut self, _platform: &mut P) {}
 
-trait State {
-    fn tick(&mut self, timestamp: f64, controller: &Controller) -> Option<Box<dyn State>>;
+    // Snapshot of a game in progress, see `RunningState::restore`
+    fn save(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Board and current piece of a game in progress as fumen
+    fn position(&self) -> Option<String> {
+        None
+    }
+
+    // Settings a game in progress was started with
+    fn settings(&self) -> Option<&Settings> {
+        None
+    }
 }
 
 struct Controller {
@@ -37,23 +76,66 @@ struct Controller {
     touch_input: input::TouchInput,
 }
 
-struct TitleState {
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LockReset {
+    // Successful moves and rotations restart the lock delay, up to the given
+    // number of times per row; after another one the piece locks as soon as
+    // it's on the ground.
+    Move(u32),
+    // Only reaching a new lowest row restarts the lock delay.
+    Step,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct LockDelay {
+    duration: f64,
+    reset: LockReset,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Garbage {
+    num_holes: usize,
+    // Chance of a garbage row's holes not lining up with the row above
+    messiness: f64,
+}
+
+#[derive(Clone)]
+struct Settings {
     board_width: usize,
     board_height: usize,
 
+    // Rows above the visible area that pieces can move and lock in
+    num_hidden_rows: usize,
+    seed: u32,
+    mode: mode::Mode,
+    rotation_system: rotation::Kind,
+    scoring_system: scoring::Kind,
+    preview_length: usize,
+    lock_delay: LockDelay,
+    garbage: Garbage,
+
+    // Custom piece set replacing the rotation system's, see `piece::parse_piece_set`
+    piece_set: Option<String>,
+}
+
+struct TitleState<P: Platform> {
     anim_clock: util::Clock,
-    animations: gfx::AnimationQueue,
+    animations: gfx::AnimationQueue<P>,
 }
 
-struct GameOverState {
-    board_width: usize,
-    board_height: usize,
+struct GameOverState<P: Platform> {
+    anim_clock: util::Clock,
+    animations: gfx::AnimationQueue<P>,
+}
 
+struct ResultsState<P: Platform> {
     anim_clock: util::Clock,
-    animations: gfx::AnimationQueue,
+    animations: gfx::AnimationQueue<P>,
 }
 
-struct RunningState {
+struct RunningState<P: Platform> {
+    settings: Settings,
+
     real_clock: util::Clock,
     anim_clock: util::Clock,
     game_clock: util::Clock,
@@ -61,24 +143,66 @@ struct RunningState {
     frame_index: u32,
 
     bag: piece::Bag,
+    garbage_rng: util::Pcg32,
     board: board::Board,
+    rotation_system: Box<dyn rotation::RotationSystem>,
+    scoring_system: Box<dyn scoring::ScoringSystem>,
+    speed_curve: Box<dyn speed::SpeedCurve>,
 
     position: util::Position,
     rotation: usize,
     fall_timer: f64,
+    garbage_timer: f64,
+    lock_timer: Option<f64>,
+    lock_resets: u32,
+    lowest_y: i32,
+    can_hold: bool,
+    last_kick: Option<(i32, i32)>,
 
     score: u32,
     num_cleared_lines: u32,
+    num_pieces: u32,
+    num_penalties: u32,
+    num_garbage_rows: u32,
+    num_risen_rows: u32,
+    start_level: u32,
     level: u32,
 
-    animations: gfx::AnimationQueue,
+    // Guideline-style counters: `Some(0)` after the first clear of a chain,
+    // `None` once the chain is broken.
+    chains: scoring::Chains,
+
+    action_timer: Option<f64>,
+    is_paused: bool,
+    animations: gfx::AnimationQueue<P>,
 }
 
-struct Game {
+pub struct Game<P: Platform> {
     controller: Controller,
-    state: Box<dyn State>,
+    settings: Settings,
+    platform: P,
+    state: Box<dyn State<P>>,
+
+    // Everything fed into the current or last game since it started, unless
+    // it's a restored saved game, which the seed can't reproduce.
+    replay: Option<replay::Replay>,
+    is_recording: bool,
+    playback: Option<Playback>,
+
+    // Of the last frame, which a game starting between frames starts on
+    last_timestamp: f64,
+}
+
+struct Playback {
+    events: Vec<replay::Event>,
+    index: usize,
+
+    // Recorded minus real timestamp, fixed by the first frame played back
+    time_offset: Option<f64>,
 }
 
+type JsGame = Game<platform::JsPlatform>;
+
 impl Controller {
     fn new() -> Self {
         Self {
@@ -88,100 +212,279 @@ impl Controller {
     }
 }
 
-impl TitleState {
-    fn new(board_width: usize, board_height: usize) -> Self {
-        let mut animations = gfx::AnimationQueue::new();
-        animations.endless(Box::new(gfx::TitleAnimation::new(board_width, board_height)));
-
-        js_api::html("top_bar", "<span class = \"title\">Press SPACE or swipe up to start</span>");
-        js_api::html("stats", "");
-
+impl Settings {
+    fn new(board_width: usize, board_height: usize, seed: u32) -> Self {
         Self {
             board_width,
             board_height,
+            num_hidden_rows: 20,
+            seed,
+            mode: mode::Mode::new(mode::Kind::Endless),
+            rotation_system: rotation::Kind::Srs,
+            scoring_system: scoring::Kind::Guideline,
+            preview_length: 5,
+            lock_delay: LockDelay {
+                duration: 500.0,
+                reset: LockReset::Move(15),
+            },
+            garbage: Garbage {
+                num_holes: 1,
+                messiness: 0.3,
+            },
+            piece_set: None,
+        }
+    }
+
+    fn encode(&self, writer: &mut encoding::Writer) {
+        writer.varint(self.board_width as u64);
+        writer.varint(self.board_height as u64);
+        writer.varint(self.num_hidden_rows as u64);
+        writer.u32(self.seed);
+        self.mode.encode(writer);
+        writer.varint(self.rotation_system.id() as u64);
+        writer.varint(self.scoring_system.id() as u64);
+        writer.varint(self.preview_length as u64);
+
+        writer.f64(self.lock_delay.duration);
+        writer.signed(match self.lock_delay.reset {
+            LockReset::Move(max_resets) => max_resets as i64,
+            LockReset::Step => -1,
+        });
+
+        writer.varint(self.garbage.num_holes as u64);
+        writer.f64(self.garbage.messiness);
+        writer.string(self.piece_set.as_deref().unwrap_or(""));
+    }
+
+    // Rejects anything `Game` couldn't have set, as it's read from files
+    fn decode(reader: &mut encoding::Reader) -> Option<Self> {
+        let (board_width, board_height) = (reader.varint()? as usize, reader.varint()? as usize);
+        let num_hidden_rows = reader.varint()? as usize;
+
+        let is_size_valid = |size: usize| { (MIN_BOARD_SIZE..=MAX_BOARD_SIZE).contains(&size) };
+        if !is_size_valid(board_width) || !is_size_valid(board_height) || num_hidden_rows > board_height {
+            return None;
+        }
+
+        let mut obj = Self::new(board_width, board_height, reader.u32()?);
+        obj.num_hidden_rows = num_hidden_rows;
+        obj.mode = mode::Mode::decode(reader)?;
+        obj.rotation_system = rotation::Kind::from_id(reader.varint()? as u32)?;
+        obj.scoring_system = scoring::Kind::from_id(reader.varint()? as u32)?;
+        obj.preview_length = util::clamp(reader.varint()? as usize, 1, piece::MAX_PEEK);
+
+        obj.lock_delay.duration = Some(reader.f64()?).filter(|x| { x.is_finite() && *x >= 0.0 })?;
+        obj.lock_delay.reset = match reader.signed()? {
+            max_resets if max_resets >= 0 => LockReset::Move(max_resets as u32),
+            _ => LockReset::Step,
+        };
+
+        obj.garbage.num_holes = Some(reader.varint()? as usize).filter(|&x| { x >= 1 })?;
+        obj.garbage.messiness = Some(reader.f64()?).filter(|x| { (0.0..=1.0).contains(x) })?;
 
+        obj.piece_set = Some(reader.string()?).filter(|x| { !x.is_empty() });
+        if let Some(text) = &obj.piece_set {
+            piece::parse_piece_set(text).ok()?;
+        }
+
+        Some(obj)
+    }
+
+    fn make_pieces(&self, rotation_system: &dyn rotation::RotationSystem) -> Vec<piece::Piece> {
+        self.piece_set.as_ref()
+            .and_then(|text| { piece::parse_piece_set(text).ok() })
+            .unwrap_or_else(|| { rotation_system.make_pieces() })
+    }
+
+    fn advance_seed(&mut self) {
+        self.seed = util::Pcg32::new(self.seed).next_u32();
+    }
+}
+
+impl<P: Platform> TitleState<P> {
+    fn new(settings: &Settings, platform: &mut P) -> Self {
+        let mut animations = gfx::AnimationQueue::new();
+        animations.endless(Box::new(gfx::TitleAnimation::new(settings.board_width, settings.board_height)));
+
+        output_menu(settings, platform);
+        platform.html("stats", "");
+        platform.html("hold", "");
+        platform.html("next", "");
+
+        Self {
             anim_clock: util::Clock::new(),
             animations,
         }
     }
+
+    fn select_mode(&self, settings: &mut Settings, offset: i32, platform: &mut P) {
+        settings.mode = mode::Mode::new(settings.mode.kind.cycle(offset));
+        settings.mode.fit_to_board(settings.board_height);
+        output_menu(settings, platform);
+    }
+
+    fn select_option(&self, settings: &mut Settings, offset: i32, platform: &mut P) {
+        settings.mode.cycle_option(offset, settings.scoring_system.create().as_ref());
+        settings.mode.fit_to_board(settings.board_height);
+        output_menu(settings, platform);
+    }
+
+    fn start_game(&self, settings: &mut Settings, platform: &mut P) -> Box<dyn State<P>> {
+        let state = Box::new(RunningState::new(settings.clone(), platform));
+        settings.advance_seed();
+
+        state
+    }
 }
 
-impl State for TitleState {
-    fn tick(&mut self, timestamp: f64, controller: &Controller) -> Option<Box<dyn State>> {
+impl<P: Platform> State<P> for TitleState<P> {
+    fn tick(&mut self, timestamp: f64, controller: &Controller, settings: &mut Settings, platform: &mut P) -> Option<Box<dyn State<P>>> {
         self.anim_clock.update(timestamp);
-        self.animations.tick(self.anim_clock.elapsed());
+        self.animations.tick(platform, self.anim_clock.elapsed());
 
         if self.animations.should_block() {
             return None;
         }
 
         if controller.button_input.is_triggered(INPUT_GAME_START) {
-            return Some(Box::new(RunningState::new(self.board_width, self.board_height)))
+            return Some(self.start_game(settings, platform));
         }
 
         let num_swipes = controller.touch_input.swipes_up(TOUCH_SWIPE_DISTANCE_THRESHOLD).count();
         if num_swipes > 0 {
-            return Some(Box::new(RunningState::new(self.board_width, self.board_height)))
+            return Some(self.start_game(settings, platform));
+        }
+
+        let is_prev = controller.button_input.is_triggered(INPUT_MENU_PREV_MODE);
+        let is_next = controller.button_input.is_triggered(INPUT_MENU_NEXT_MODE);
+
+        let num_swipes_left = controller.touch_input.swipes_left(TOUCH_SWIPE_DISTANCE_THRESHOLD).count();
+        let num_swipes_right = controller.touch_input.swipes_right(TOUCH_SWIPE_DISTANCE_THRESHOLD).count();
+
+        if is_prev || num_swipes_left > 0 {
+            self.select_mode(settings, -1, platform);
+        } else if is_next || num_swipes_right > 0 {
+            self.select_mode(settings, 1, platform);
+        }
+
+        let is_prev_option = controller.button_input.is_triggered(INPUT_MENU_PREV_OPTION);
+        let is_next_option = controller.button_input.is_triggered(INPUT_MENU_NEXT_OPTION);
+
+        let num_swipes_down = controller.touch_input.swipes_down(TOUCH_SWIPE_DISTANCE_THRESHOLD).count();
+
+        if is_prev_option {
+            self.select_option(settings, -1, platform);
+        } else if is_next_option || num_swipes_down > 0 {
+            self.select_option(settings, 1, platform);
         }
 
         None
     }
 }
 
-impl GameOverState {
-    fn new(board_width: usize, board_height: usize) -> Self {
-        let anim = gfx::GameOverAnimation::new(board_width, board_height);
+impl<P: Platform> GameOverState<P> {
+    fn new(settings: &Settings, reveal: Option<gfx::RevealAnimation>, platform: &mut P) -> Self {
+        let anim = gfx::GameOverAnimation::new(settings.board_width, settings.board_height);
 
         let mut animations = gfx::AnimationQueue::new();
-        animations.schedule(0.0, ANIMATION_DURATION_GAME_OVER, Box::new(anim));
+        let mut start = 0.0;
 
-        js_api::html("top_bar", "<span class = \"game-over\">GAME OVER</span>");
+        if let Some(reveal) = reveal {
+            animations.schedule(0.0, ANIMATION_DURATION_REVEAL, Box::new(reveal));
+            start = ANIMATION_DURATION_REVEAL + REVEAL_DURATION;
+        }
 
-        Self {
-            board_width,
-            board_height,
+        animations.schedule(start, ANIMATION_DURATION_GAME_OVER, Box::new(anim));
+
+        platform.html("top_bar", "<span class = \"game-over\">GAME OVER</span>");
 
+        Self {
             anim_clock: util::Clock::new(),
             animations,
         }
     }
 }
 
-impl State for GameOverState {
-    fn tick(&mut self, timestamp: f64, controller: &Controller) -> Option<Box<dyn State>> {
+impl<P: Platform> State<P> for GameOverState<P> {
+    fn tick(&mut self, timestamp: f64, controller: &Controller, settings: &mut Settings, platform: &mut P) -> Option<Box<dyn State<P>>> {
         self.anim_clock.update(timestamp);
-        self.animations.tick(self.anim_clock.elapsed());
+        self.animations.tick(platform, self.anim_clock.elapsed());
 
         if self.animations.should_block() {
             return None;
         }
 
-        let is_start = controller.button_input.is_triggered(INPUT_GAME_START);
-        let is_stop = controller.button_input.is_triggered(INPUT_GAME_STOP);
+        if is_dismissed(controller) {
+            return Some(Box::new(TitleState::new(settings, platform)));
+        }
+
+        None
+    }
+}
+
+impl<P: Platform> ResultsState<P> {
+    fn new(settings: &Settings, headline: &str, results: &[(&str, String)], platform: &mut P) -> Self {
+        let anim = gfx::GameOverAnimation::new(settings.board_width, settings.board_height);
+
+        let mut animations = gfx::AnimationQueue::new();
+        animations.schedule(0.0, ANIMATION_DURATION_GAME_OVER, Box::new(anim));
+
+        platform.html("top_bar", &format!("<span class = \"results\">{}</span>", headline));
+        output_values(platform, "stats", results);
+
+        Self {
+            anim_clock: util::Clock::new(),
+            animations,
+        }
+    }
+}
+
+impl<P: Platform> State<P> for ResultsState<P> {
+    fn tick(&mut self, timestamp: f64, controller: &Controller, settings: &mut Settings, platform: &mut P) -> Option<Box<dyn State<P>>> {
+        self.anim_clock.update(timestamp);
+        self.animations.tick(platform, self.anim_clock.elapsed());
 
-        if is_start || is_stop {
-            return Some(Box::new(TitleState::new(self.board_width, self.board_height)));
+        if self.animations.should_block() {
+            return None;
         }
 
-        let num_swipes = controller.touch_input.swipes_up(TOUCH_SWIPE_DISTANCE_THRESHOLD).count();
-        if num_swipes > 0 {
-            return Some(Box::new(TitleState::new(self.board_width, self.board_height)));
+        if is_dismissed(controller) {
+            return Some(Box::new(TitleState::new(settings, platform)));
         }
 
         None
     }
 }
 
-impl RunningState {
-    fn new(board_width: usize, board_height: usize) -> Self {
-        js_api::html("top_bar", "");
+impl<P: Platform> RunningState<P> {
+    fn new(settings: Settings, platform: &mut P) -> Self {
+        platform.html("top_bar", "");
+
+        let mut board = board::Board::new(settings.board_width, settings.board_height, settings.num_hidden_rows);
+        let mut garbage_rng = util::Pcg32::new(!settings.seed);
+
+        let num_garbage_rows = match settings.mode.goal {
+            mode::Goal::Garbage(n) => n.min(mode::max_garbage_rows(settings.board_height)),
+            _ => 0,
+        };
+
+        let garbage = settings.garbage;
+        board.insert_garbage(num_garbage_rows as usize, garbage.num_holes, garbage.messiness, &mut garbage_rng);
 
-        let board = board::Board::new(board_width, board_height);
-        let bag = piece::Bag::new(piece::make_standard());
+        let rotation_system = settings.rotation_system.create();
+        let bag = piece::Bag::new(settings.make_pieces(rotation_system.as_ref()), settings.mode.randomizer, settings.seed);
+        let scoring_system = settings.scoring_system.create();
+        let speed_curve = settings.mode.speed.create(scoring_system.min_level());
+        let start_level = settings.mode.start_level(scoring_system.as_ref());
         let rotation = 0;
         let position = board.initial_position(bag.current(), rotation);
 
-        Self {
+        output_hold(platform, None, true);
+        output_next(platform, bag.peek(settings.preview_length));
+
+        let mut obj = Self {
+            settings,
+
             real_clock: util::Clock::new(),
             anim_clock: util::Clock::new(),
             game_clock: util::Clock::new(),
@@ -190,57 +493,444 @@ impl RunningState {
 
             board,
             bag,
+            garbage_rng,
+            rotation_system,
+            scoring_system,
+            speed_curve,
 
             position,
             rotation,
             fall_timer: 0.0,
+            garbage_timer: 0.0,
+            lock_timer: None,
+            lock_resets: 0,
+            lowest_y: position.y,
+            can_hold: true,
+            last_kick: None,
 
             score: 0,
             num_cleared_lines: 0,
-            level: 1,
+            num_pieces: 0,
+            num_penalties: 0,
+            num_garbage_rows,
+            num_risen_rows: 0,
+            start_level,
+            level: start_level,
+
+            chains: scoring::Chains::default(),
 
+            action_timer: None,
+            is_paused: false,
             animations: gfx::AnimationQueue::new(),
+        };
+
+        // The first piece drops into view like all others, there's always
+        // room for it above the garbage.
+        obj.spawn_piece();
+        obj
+    }
+
+    // Animations and the action text aren't saved, and restored games start
+    // out paused.
+    fn encode(&self, writer: &mut encoding::Writer) {
+        self.settings.encode(writer);
+
+        self.real_clock.encode(writer);
+        self.anim_clock.encode(writer);
+        self.game_clock.encode(writer);
+        writer.varint(self.frame_index as u64);
+
+        self.bag.encode(writer);
+        self.garbage_rng.encode(writer);
+        self.board.encode(writer);
+
+        writer.signed(self.position.x as i64);
+        writer.signed(self.position.y as i64);
+        writer.varint(self.rotation as u64);
+        writer.f64(self.fall_timer);
+        writer.f64(self.garbage_timer);
+        writer.optional_f64(self.lock_timer);
+        writer.varint(self.lock_resets as u64);
+        writer.signed(self.lowest_y as i64);
+        writer.u8(self.can_hold as u8);
+
+        match self.last_kick {
+            Some((x, y)) => {
+                writer.u8(1);
+                writer.signed(x as i64);
+                writer.signed(y as i64);
+            },
+            None => writer.u8(0),
+        }
+
+        writer.varint(self.score as u64);
+        writer.varint(self.num_cleared_lines as u64);
+        writer.varint(self.num_pieces as u64);
+        writer.varint(self.num_penalties as u64);
+        writer.varint(self.num_garbage_rows as u64);
+        writer.varint(self.num_risen_rows as u64);
+        writer.varint(self.start_level as u64);
+        writer.varint(self.level as u64);
+
+        writer.optional_varint(self.chains.combo.map(|x| { x as u64 }));
+        writer.optional_varint(self.chains.back_to_back.map(|x| { x as u64 }));
+    }
+
+    fn restore(reader: &mut encoding::Reader, platform: &mut P) -> Option<Self> {
+        let settings = Settings::decode(reader)?;
+        let mut obj = Self::new(settings, platform);
+
+        obj.real_clock = util::Clock::decode(reader)?;
+        obj.anim_clock = util::Clock::decode(reader)?;
+        obj.game_clock = util::Clock::decode(reader)?;
+        obj.frame_index = reader.varint()? as u32;
+
+        obj.bag = piece::Bag::decode(obj.settings.make_pieces(obj.rotation_system.as_ref()), obj.settings.mode.randomizer, reader)?;
+        obj.garbage_rng = util::Pcg32::decode(reader)?;
+        obj.board = board::Board::decode(reader)?;
+
+        obj.position = util::Position::new(reader.signed()? as i32, reader.signed()? as i32);
+        obj.rotation = reader.varint()? as usize % 4;
+        obj.fall_timer = reader.f64()?;
+        obj.garbage_timer = reader.f64()?;
+        obj.lock_timer = reader.optional_f64()?;
+        obj.lock_resets = reader.varint()? as u32;
+        obj.lowest_y = reader.signed()? as i32;
+        obj.can_hold = reader.u8()? != 0;
+
+        obj.last_kick = match reader.u8()? {
+            0 => None,
+            _ => Some((reader.signed()? as i32, reader.signed()? as i32)),
+        };
+
+        obj.score = reader.varint()? as u32;
+        obj.num_cleared_lines = reader.varint()? as u32;
+        obj.num_pieces = reader.varint()? as u32;
+        obj.num_penalties = reader.varint()? as u32;
+        obj.num_garbage_rows = reader.varint()? as u32;
+        obj.num_risen_rows = reader.varint()? as u32;
+        obj.start_level = reader.varint()? as u32;
+        obj.level = reader.varint()? as u32;
+
+        obj.chains.combo = reader.optional_varint()?.map(|x| { x as u32 });
+        obj.chains.back_to_back = reader.optional_varint()?.map(|x| { x as u32 });
+
+        let is_board_valid = obj.board.width() == obj.settings.board_width
+            && obj.board.height() == obj.settings.board_height
+            && obj.board.num_hidden_rows() == obj.settings.num_hidden_rows;
+        if !is_board_valid || obj.board.collides(obj.bag.current(), &obj.position, obj.rotation) {
+            return None;
+        }
+
+        let are_timers_valid = [obj.fall_timer, obj.garbage_timer, obj.lock_timer.unwrap_or(0.0)].iter()
+            .all(|x| { x.is_finite() });
+        if !are_timers_valid || obj.num_garbage_rows as usize > obj.board.height() {
+            return None;
+        }
+
+        // Levels the mode and scoring system can reach, as in `Mode::start_level`
+        let levels = match obj.settings.mode.goal {
+            mode::Goal::Level(n) if obj.settings.mode.has_section_levels => 0..=n,
+            _ => obj.scoring_system.min_level()..=obj.scoring_system.max_level(),
+        };
+        if !levels.contains(&obj.start_level) || !levels.contains(&obj.level) {
+            return None;
+        }
+
+        output_hold(platform, obj.bag.held(), obj.can_hold);
+        output_next(platform, obj.bag.peek(obj.settings.preview_length));
+        obj.output_stats(platform);
+        obj.set_paused(true, platform);
+
+        Some(obj)
+    }
+
+    // Starts from the position of a fumen page instead of an empty board,
+    // with the page's piece in play if the piece set has it. Otherwise the
+    // next piece spawns as usual, which may top out on a high stack.
+    fn load_position(&mut self, page: &fumen::Page, platform: &mut P) -> Option<Box<dyn State<P>>> {
+        self.board.load_fumen(page, self.bag.pieces());
+
+        if let Some(comment) = &page.comment {
+            platform.html("top_bar", &format!("<span class = \"action\">{}</span>", escape_html(comment)));
+            self.action_timer = Some(self.real_clock.elapsed());
+        }
+
+        let active = page.piece
+            .and_then(|x| { self.board.fumen_piece_position(&x, self.bag.pieces()) })
+            .filter(|(index, position, rotation)| { !self.board.collides(&self.bag.pieces()[*index], position, *rotation) });
+
+        if let Some((index, position, rotation)) = active {
+            self.bag.push_current(index);
+            output_next(platform, self.bag.peek(self.settings.preview_length));
+
+            self.position = position;
+            self.rotation = rotation;
+            self.lowest_y = position.y;
+        } else if !self.spawn_piece() {
+            return self.top_out(platform);
+        }
+
+        None
+    }
+
+    fn game_over(&self, platform: &mut P) -> Box<dyn State<P>> {
+        let reveal = self.settings.mode.fade.map(|_| {
+            let blocks = self.board.iter_blocks()
+                .map(|(x, y, color)| { (x, y, color.clone()) })
+                .collect();
+
+            gfx::RevealAnimation::new(blocks, self.board.height())
+        });
+
+        return Box::new(GameOverState::new(&self.settings, reveal, platform));
+    }
+
+    // Zen mode clears the upper half of the board instead of ending the game
+    fn top_out(&mut self, platform: &mut P) -> Option<Box<dyn State<P>>> {
+        if self.settings.mode.has_rising_garbage {
+            return Some(self.finish(platform, "GAME OVER"));
+        }
+
+        if self.settings.mode.has_top_out {
+            return Some(self.game_over(platform));
+        }
+
+        let cleared_rows = self.board.clear_top_rows(self.board.height() / 2);
+        self.num_penalties += 1;
+
+        platform.html("top_bar", "<span class = \"action\">PENALTY</span>");
+        self.action_timer = Some(self.real_clock.elapsed());
+
+        let anim = gfx::LineClearAnimation::new(cleared_rows, self.board.width());
+        let delay = self.speed_curve.line_clear_delay(self.level);
+        self.animations.schedule(self.anim_clock.elapsed(), delay, Box::new(anim));
+
+        if !self.spawn_piece() {
+            Some(self.game_over(platform))
+        } else {
+            None
+        }
+    }
+
+    fn finish(&self, platform: &mut P, headline: &str) -> Box<dyn State<P>> {
+        let results = [
+            ("TIME", util::format_timestamp(self.real_clock.elapsed().min(self.time_limit()))),
+            ("SCORE", self.score.to_string()),
+            ("LINES", self.num_cleared_lines.to_string()),
+            ("LEVEL", self.level.to_string()),
+            ("PIECES", self.num_pieces.to_string()),
+        ];
+
+        Box::new(ResultsState::new(&self.settings, headline, &results, platform))
+    }
+
+    fn time_limit(&self) -> f64 {
+        match self.settings.mode.goal {
+            mode::Goal::Time(limit) => limit,
+            _ => f64::INFINITY,
+        }
+    }
+
+    fn is_time_up(&self) -> bool {
+        self.real_clock.elapsed() >= self.time_limit()
+    }
+
+    fn is_goal_reached(&self) -> bool {
+        match self.settings.mode.goal {
+            mode::Goal::None => false,
+            mode::Goal::Lines(n) => self.num_cleared_lines >= n,
+            mode::Goal::Time(_) => false,
+            mode::Goal::Garbage(_) => self.num_garbage_rows == 0,
+            mode::Goal::Level(n) => self.level >= n,
+        }
+    }
+
+    // Level to calculate the score of a line clear with
+    fn scoring_level(&self) -> u32 {
+        if self.settings.mode.has_section_levels {
+            self.scoring_system.min_level() + self.level / 100
+        } else {
+            self.level
         }
     }
 
-    fn game_over(&self) -> Box<dyn State> {
-        return Box::new(GameOverState::new(self.board.width(), self.board.height()));
+    fn advance_section_level(&mut self, num_levels: u32, is_line_clear: bool) {
+        if !self.settings.mode.has_section_levels {
+            return;
+        }
+
+        let max_level = match self.settings.mode.goal {
+            mode::Goal::Level(n) => n,
+            _ => u32::MAX,
+        };
+
+        // Only clearing lines advances past the end of a section
+        let is_stopped = self.level % 100 == 99 || self.level + 1 == max_level;
+        if is_line_clear || !is_stopped {
+            self.level = (self.level + num_levels).min(max_level);
+        }
     }
 
     fn reset_fall_timer(&mut self) {
         self.fall_timer = self.game_clock.elapsed();
     }
 
-    fn new_piece(&mut self) -> bool {
+    // Counts up to one past the maximum, which marks a refused reset
+    fn reset_lock_timer(&mut self) {
+        if let LockReset::Move(max_resets) = self.settings.lock_delay.reset {
+            if self.lock_timer.is_some() {
+                self.lock_resets = self.lock_resets.saturating_add(1).min(max_resets.saturating_add(1));
+                if self.lock_resets <= max_resets {
+                    self.lock_timer = Some(self.game_clock.elapsed());
+                }
+            }
+        }
+    }
+
+    fn update_lowest_row(&mut self) {
+        if self.position.y > self.lowest_y {
+            self.lowest_y = self.position.y;
+            self.lock_timer = None;
+            self.lock_resets = 0;
+        }
+    }
+
+    fn is_grounded(&self) -> bool {
+        let piece = self.bag.current();
+        self.board.collides(piece, &self.position.add_y(1), self.rotation)
+    }
+
+    fn is_lock_due(&self) -> bool {
+        let is_expired = self.lock_timer
+            .map(|x| { self.game_clock.elapsed() - x >= self.settings.lock_delay.duration })
+            .unwrap_or(false);
+
+        let is_exhausted = match self.settings.lock_delay.reset {
+            LockReset::Move(max_resets) => self.lock_resets > max_resets,
+            LockReset::Step => false,
+        };
+
+        is_expired || is_exhausted
+    }
+
+    fn new_piece(&mut self, platform: &mut P) -> bool {
         self.bag.advance();
+        output_next(platform, self.bag.peek(self.settings.preview_length));
+
+        if !self.can_hold {
+            self.can_hold = true;
+            output_hold(platform, self.bag.held(), self.can_hold);
+        }
 
+        self.advance_section_level(1, false);
+        self.spawn_piece()
+    }
+
+    fn spawn_piece(&mut self) -> bool {
         let rotation = 0;
-        let position = self.board.initial_position(self.bag.current(), rotation);
+        let mut position = self.board.initial_position(self.bag.current(), rotation);
 
+        // Block out
         if self.board.collides(self.bag.current(), &position, rotation) {
             false
         } else {
+            // Pieces spawning above the visible area drop into view right
+            // away if nothing is in the way.
+            let next_position = position.add_y(1);
+            if self.board.num_hidden_rows() > 0 && !self.board.collides(self.bag.current(), &next_position, rotation) {
+                position = next_position;
+            }
+
+            if self.speed_curve.gravity(self.level) >= speed::INSTANT_GRAVITY {
+                position = self.board.find_drop_position(self.bag.current(), &position, rotation);
+            }
+
             self.reset_fall_timer();
 
             self.rotation = rotation;
             self.position = position;
 
+            self.lock_timer = None;
+            self.lock_resets = 0;
+            self.lowest_y = position.y;
+            self.last_kick = None;
+
             true
         }
     }
 
-    fn place_piece(&mut self) {
+    fn place_piece(&mut self, platform: &mut P) {
         let piece = self.bag.current();
+
+        let spin = self.last_kick
+            .map(|kick| { rotation::detect_t_spin(&self.board, piece, &self.position, self.rotation, kick) })
+            .unwrap_or(rotation::Spin::None);
+
         self.board.put_piece(piece, &self.position, self.rotation);
+        self.num_pieces += 1;
 
         let cleared_lines = self.board.clear_lines();
+        let num_lines = cleared_lines.len();
+
+        // Garbage is only ever inserted at the bottom, so the remaining
+        // garbage rows are always the lowest ones on the board.
+        let first_garbage_row = (self.board.height() - self.num_garbage_rows as usize) as i32;
+        self.num_garbage_rows -= cleared_lines.iter().filter(|&&y| { y >= first_garbage_row }).count() as u32;
+
+        let clear = self.chains.add(num_lines, spin);
+        let combo = clear.combo;
+
+        self.score += self.scoring_system.clear_score(&clear, self.scoring_level());
+
+        let mut texts: Vec<String> = Vec::new();
+        if let Some(text) = action_text(spin, num_lines) {
+            if clear.is_back_to_back {
+                texts.push("BACK-TO-BACK".to_string());
+            }
+            texts.push(text);
+        }
+        if combo > 0 {
+            texts.push(format!("{} COMBO", combo));
+        }
+
+        if !texts.is_empty() {
+            platform.html("top_bar", &format!("<span class = \"action\">{}</span>", texts.join(" ")));
+            self.action_timer = Some(self.real_clock.elapsed());
+        }
+
         if !cleared_lines.is_empty() {
-            self.score += 100 * (1 << (cleared_lines.len() - 1)) + 50 * (self.level - 1);
             self.num_cleared_lines += cleared_lines.len() as u32;
-            self.level = (1 + self.num_cleared_lines / 10).min(20);
+            if self.settings.mode.has_level_progression {
+                self.level = self.scoring_system.level(self.start_level, self.num_cleared_lines);
+            }
+
+            let visible_rows = cleared_lines.iter().filter(|&&y| { y >= 0 }).map(|&y| { y as usize }).collect();
+            let anim = gfx::LineClearAnimation::new(visible_rows, self.board.width());
+            let delay = self.speed_curve.line_clear_delay(self.level);
+            self.animations.schedule(self.anim_clock.elapsed(), delay, Box::new(anim));
+        }
+
+        let are = self.speed_curve.are(self.level);
+        if are > 0.0 {
+            let delay = if num_lines > 0 { self.speed_curve.line_clear_delay(self.level) + are } else { are };
+            self.animations.schedule(self.anim_clock.elapsed(), delay, Box::new(gfx::DelayAnimation));
+        }
 
-            let anim = gfx::LineClearAnimation::new(cleared_lines, self.board.width());
-            self.animations.schedule(self.anim_clock.elapsed(), ANIMATION_DURATION_LINE_CLEAR, Box::new(anim));
+        self.advance_section_level(num_lines as u32, true);
+    }
+
+    fn lock_piece(&mut self, platform: &mut P) -> Option<Box<dyn State<P>>> {
+        // Lock out: the piece locked without any part of it in view
+        let is_hidden = self.board.is_hidden(self.bag.current(), &self.position, self.rotation);
+        self.place_piece(platform);
+
+        if self.is_goal_reached() {
+            Some(self.finish(platform, "CLEAR!"))
+        } else if !self.new_piece(platform) || is_hidden {
+            self.top_out(platform)
+        } else {
+            None
         }
     }
 
@@ -255,16 +945,23 @@ impl RunningState {
             }
         };
 
+        let orig_position = self.position;
+
         let piece = self.bag.current();
         for _ in 0..offset.abs() {
             let new_position = self.position.add_x(step);
             if self.board.collides(piece, &new_position, self.rotation) {
-                return false;
+                break;
             }
             self.position = new_position;
         }
 
-        true
+        if self.position != orig_position {
+            self.reset_lock_timer();
+            self.last_kick = None;
+        }
+
+        self.position.x == orig_position.x + offset
     }
 
     fn move_piece_y(&mut self, offset: i32) -> bool {
@@ -272,16 +969,34 @@ impl RunningState {
             return false;
         }
 
+        let orig_position = self.position;
+
         let piece = self.bag.current();
         for _ in 0..offset.abs() {
             let new_position = self.position.add_y(1);
             if self.board.collides(piece, &new_position, self.rotation) {
-                return false;
+                break;
             }
             self.position = new_position;
         }
 
-        true
+        if self.position != orig_position {
+            self.update_lowest_row();
+            self.last_kick = None;
+        }
+
+        self.position.y == orig_position.y + offset
+    }
+
+    fn soft_drop_piece(&mut self, offset: i32) {
+        let orig_position = self.position;
+
+        if self.move_piece_y(offset) {
+            self.reset_fall_timer();
+        }
+
+        let num_rows = (self.position.y - orig_position.y) as u32;
+        self.score += self.scoring_system.soft_drop_score(num_rows);
     }
 
     fn rotate_piece(&mut self, offset: i32) -> bool {
@@ -297,29 +1012,34 @@ impl RunningState {
         for _ in 0..offset.abs() {
             let new_rotation = {
                 if offset > 0 {
-                    self.rotation.wrapping_add(1)
+                    (self.rotation + 1) % 4
                 } else {
-                    self.rotation.wrapping_sub(1)
+                    (self.rotation + 3) % 4
                 }
             };
 
-            'outer:
-            for &y in &[0, 1, 2] {
-                for &x in &[0, 1, -1, 2, -2] {
-                    let pos = util::Position::new(self.position.x + x, self.position.y + y);
-                    if !self.board.collides(piece, &pos, new_rotation) {
-                        self.position = pos;
-                        self.rotation = new_rotation;
-                        break 'outer;
-                    }
+            let kicks = self.rotation_system.kicks(&self.board, piece, &self.position, self.rotation, new_rotation);
+            for (x, y) in kicks {
+                let pos = util::Position::new(self.position.x + x, self.position.y + y);
+                if !self.board.collides(piece, &pos, new_rotation) {
+                    self.position = pos;
+                    self.rotation = new_rotation;
+                    self.last_kick = Some((x, y));
+                    break;
                 }
             }
         }
 
-        self.position != orig_position || self.rotation != orig_rotation
+        let is_rotated = self.position != orig_position || self.rotation != orig_rotation;
+        if is_rotated {
+            self.reset_lock_timer();
+            self.update_lowest_row();
+        }
+
+        is_rotated
     }
 
-    fn hard_drop_piece(&mut self) -> Option<Box<dyn State>> {
+    fn hard_drop_piece(&mut self, platform: &mut P) -> Option<Box<dyn State<P>>> {
         if self.game_clock.is_suspended() {
             return None;
         }
@@ -336,49 +1056,81 @@ impl RunningState {
                 drop_pos.y);
 
             self.animations.schedule(self.anim_clock.elapsed(), ANIMATION_DURATION_HARD_DROP, Box::new(anim));
+            self.last_kick = None;
         }
 
+        let num_rows = (drop_pos.y - self.position.y) as u32;
+        self.score += self.scoring_system.hard_drop_score(num_rows);
+
         self.position = drop_pos;
-        self.place_piece();
+        self.lock_piece(platform)
+    }
+
+    fn hold_piece(&mut self, platform: &mut P) -> Option<Box<dyn State<P>>> {
+        if !self.can_hold {
+            return None;
+        }
 
-        if !self.new_piece() {
-            Some(self.game_over())
+        self.bag.hold();
+        self.can_hold = false;
+        output_hold(platform, self.bag.held(), self.can_hold);
+        output_next(platform, self.bag.peek(self.settings.preview_length));
+
+        if !self.spawn_piece() {
+            self.top_out(platform)
         } else {
             None
         }
     }
 
-    fn handle_input_misc(&mut self, controller: &Controller) -> Option<Box<dyn State>> {
+    fn handle_input_misc(&mut self, controller: &Controller, platform: &mut P) -> Option<Box<dyn State<P>>> {
         if controller.button_input.is_triggered(INPUT_GAME_STOP) {
-            return Some(self.game_over());
+            return Some(self.game_over(platform));
         }
 
         None
     }
 
-    fn handle_input_drop(&mut self, controller: &Controller) -> Option<Box<dyn State>> {
+    fn handle_input_drop(&mut self, controller: &Controller, platform: &mut P) -> Option<Box<dyn State<P>>> {
         if self.game_clock.is_suspended() {
             return None;
         }
 
         if controller.button_input.is_triggered(INPUT_HARD_DROP) {
-            return self.hard_drop_piece();
+            return self.hard_drop_piece(platform);
         }
 
         let num_swipes = controller.touch_input.swipes_up(TOUCH_SWIPE_DISTANCE_THRESHOLD).count();
         if num_swipes > 0 {
-            return self.hard_drop_piece();
+            return self.hard_drop_piece(platform);
         }
 
         None
     }
 
-    fn handle_input_move(&mut self, controller: &Controller) -> Option<Box<dyn State>> {
+    fn handle_input_hold(&mut self, controller: &Controller, platform: &mut P) -> Option<Box<dyn State<P>>> {
         if self.game_clock.is_suspended() {
             return None;
         }
 
-        let ts_left = controller.button_input.get_button_press_timestamp(INPUT_MOVE_LEFT);
+        if controller.button_input.is_triggered(INPUT_HOLD) {
+            return self.hold_piece(platform);
+        }
+
+        let num_long_presses = controller.touch_input.long_presses(TOUCH_TAP_DISTANCE_THRESHOLD, TOUCH_LONG_PRESS_PERIOD_THRESHOLD).count();
+        if num_long_presses > 0 {
+            return self.hold_piece(platform);
+        }
+
+        None
+    }
+
+    fn handle_input_move(&mut self, controller: &Controller) -> Option<Box<dyn State<P>>> {
+        if self.game_clock.is_suspended() {
+            return None;
+        }
+
+        let ts_left = controller.button_input.get_button_press_timestamp(INPUT_MOVE_LEFT);
         let ts_right = controller.button_input.get_button_press_timestamp(INPUT_MOVE_RIGHT);
 
         let is_left = controller.button_input.is_triggered_or_repeat(INPUT_MOVE_LEFT, INITIAL_DELAY_MOVE, REPEAT_DELAY_MOVE);
@@ -392,9 +1144,7 @@ impl RunningState {
 
         let is_soft_drop = controller.button_input.is_triggered_or_repeat(INPUT_SOFT_DROP, INITIAL_DELAY_SOFT_DROP, REPEAT_DELAY_SOFT_DROP);
         if is_soft_drop {
-            if self.move_piece_y(1) {
-                self.reset_fall_timer();
-            }
+            self.soft_drop_piece(1);
         }
 
         for (_, (start, prev, curr)) in controller.touch_input.motions() {
@@ -404,16 +1154,14 @@ impl RunningState {
             if x_offset != 0 || y_offset != 0 {
                 self.move_piece_x(x_offset);
 
-                if self.move_piece_y(y_offset) {
-                    self.reset_fall_timer();
-                }
+                self.soft_drop_piece(y_offset);
             }
         }
 
         None
     }
 
-    fn handle_input_rotate(&mut self, controller: &Controller) -> Option<Box<dyn State>> {
+    fn handle_input_rotate(&mut self, controller: &Controller) -> Option<Box<dyn State<P>>> {
         if self.game_clock.is_suspended() {
             return None;
         }
@@ -435,175 +1183,1624 @@ impl RunningState {
         None
     }
 
-    fn handle_input(&mut self, controller: &Controller) -> Option<Box<dyn State>> {
+    fn handle_input(&mut self, controller: &Controller, platform: &mut P) -> Option<Box<dyn State<P>>> {
         None
-            .or_else(|| { self.handle_input_misc(&controller) })
-            .or_else(|| { self.handle_input_drop(&controller) })
+            .or_else(|| { self.handle_input_misc(&controller, platform) })
+            .or_else(|| { self.handle_input_hold(controller, platform) })
+            .or_else(|| { self.handle_input_drop(&controller, platform) })
             .or_else(|| { self.handle_input_move(&controller) })
             .or_else(|| { self.handle_input_rotate(&controller) })
     }
 
-    fn apply_gravity(&mut self) -> Option<Box<dyn State>> {
+    fn apply_gravity(&mut self, platform: &mut P) -> Option<Box<dyn State<P>>> {
         if self.game_clock.is_suspended() {
             return None;
         }
 
-        let gravity = 0.904974583f64.powf(self.level as f64 - 1.0) * 1000.0;
-        if self.game_clock.has_passed_multiple_of(gravity, self.fall_timer) {
-            if !self.move_piece_y(1) {
-                self.place_piece();
-                if !self.new_piece() {
-                    return Some(self.game_over());
-                }
+        let period = speed::FRAME_DURATION / self.speed_curve.gravity(self.level);
+        let num_rows = self.game_clock.num_multiples_passed(period, self.fall_timer);
+        if num_rows > 0 {
+            self.move_piece_y(num_rows.min(self.board.height() as u32) as i32);
+        }
+
+        if self.is_grounded() {
+            if self.lock_timer.is_none() {
+                self.lock_timer = Some(self.game_clock.elapsed());
+            }
+
+            if self.is_lock_due() {
+                return self.lock_piece(platform);
+            }
+        }
+
+        None
+    }
+
+    fn apply_rising_garbage(&mut self, platform: &mut P) -> Option<Box<dyn State<P>>> {
+        if self.game_clock.is_suspended() || !self.settings.mode.has_rising_garbage {
+            return None;
+        }
+
+        let interval = self.settings.mode.garbage_interval(self.num_risen_rows);
+        if !self.game_clock.has_passed_multiple_of(interval, self.garbage_timer) {
+            return None;
+        }
+
+        self.garbage_timer = self.game_clock.elapsed();
+        self.num_risen_rows += 1;
+        self.num_garbage_rows = (self.num_garbage_rows + 1).min(self.board.height() as u32);
+
+        let garbage = self.settings.garbage;
+        if !self.board.insert_garbage(1, garbage.num_holes, garbage.messiness, &mut self.garbage_rng) {
+            return self.top_out(platform);
+        }
+
+        // Push the falling piece up along with the stack if it's in the way
+        let piece = self.bag.current();
+        if self.board.collides(piece, &self.position, self.rotation) {
+            let position = self.position.add_y(-1);
+            if self.board.collides(piece, &position, self.rotation) {
+                return self.top_out(platform);
             }
+
+            self.position = position;
         }
 
         None
     }
 
-    fn output_stats(&self) {
-        let text = format!(
-            r#"
-            <div>
-                <span class = "name">TIME</span>
-                <span class = "value">{}</span>
-            </div>
-            <div>
-                <span class = "name">SCORE</span>
-                <span class = "value">{}</span>
-            </div>
-            <div>
-                <span class = "name">LINES</span>
-                <span class = "value">{}</span>
-            </div>
-            <div>
-                <span class = "name">LEVEL</span>
-                <span class = "value">{}</span>
-            </div>
-            "#,
-            util::format_timestamp(self.real_clock.elapsed()),
-            self.score,
-            self.num_cleared_lines,
-            self.level,
-        );
-
-        js_api::html("stats", text);
-    }
-
-    fn update(&mut self, controller: &Controller) -> Option<Box<dyn State>> {
-        let new_state = self.handle_input(controller).or_else(|| { self.apply_gravity() });
+    fn output_stats(&self, platform: &mut P) {
+        // Count down in timed modes
+        let time = match self.settings.mode.goal {
+            mode::Goal::Time(limit) => (limit - self.real_clock.elapsed()).max(0.0),
+            _ => self.real_clock.elapsed(),
+        };
+
+        let mut stats = vec![
+            ("TIME", util::format_timestamp(time)),
+            ("SCORE", self.score.to_string()),
+            ("LINES", match self.settings.mode.goal {
+                mode::Goal::Lines(n) => format!("{}/{}", self.num_cleared_lines, n),
+                _ => self.num_cleared_lines.to_string(),
+            }),
+            ("LEVEL", match self.settings.mode.goal {
+                mode::Goal::Level(n) if self.settings.mode.has_section_levels => {
+                    format!("{}/{}", self.level, (self.level / 100 + 1).saturating_mul(100).min(n))
+                },
+                _ => self.level.to_string(),
+            }),
+            ("COMBO", self.chains.combo.unwrap_or(0).to_string()),
+            ("B2B", self.chains.back_to_back.unwrap_or(0).to_string()),
+        ];
+
+        if !self.settings.mode.has_top_out {
+            stats.push(("PENALTY", self.num_penalties.to_string()));
+        }
+
+        if let mode::Goal::Garbage(_) = self.settings.mode.goal {
+            stats.push(("GARBAGE", self.num_garbage_rows.to_string()));
+        }
+
+        output_values(platform, "stats", &stats);
+    }
+
+    // Stops all clocks and hides the board, hold and next pieces, so the pause
+    // can't be used to plan ahead
+    fn set_paused(&mut self, is_paused: bool, platform: &mut P) {
+        if is_paused == self.is_paused {
+            return;
+        }
+
+        self.is_paused = is_paused;
+        self.real_clock.toggle(is_paused);
+
+        if is_paused {
+            self.anim_clock.suspend();
+            self.game_clock.suspend();
+
+            for y in 0..self.board.height() {
+                for x in 0..self.board.width() {
+                    platform.draw_block(x as u32, y as u32, 0);
+                }
+            }
+
+            output_hold(platform, None, true);
+            output_next(platform, &[]);
+
+            platform.html("overlay", r#"
+                <span class = "paused">PAUSED</span>
+                <span class = "hint">Press P or tap with two fingers to resume</span>
+            "#);
+        } else {
+            output_hold(platform, self.bag.held(), self.can_hold);
+            output_next(platform, self.bag.peek(self.settings.preview_length));
+
+            platform.html("overlay", "");
+        }
+    }
+
+    fn update(&mut self, controller: &Controller, platform: &mut P) -> Option<Box<dyn State<P>>> {
+        if self.is_time_up() {
+            return Some(self.finish(platform, "TIME UP!"));
+        }
+
+        self.board.set_time(self.game_clock.elapsed());
+
+        let new_state = self.handle_input(controller, platform)
+            .or_else(|| { self.apply_gravity(platform) })
+            .or_else(|| { self.apply_rising_garbage(platform) });
         if new_state.is_some() {
             return new_state;
         }
 
         self.anim_clock.toggle(self.animations.is_empty());
-        self.animations.tick(self.anim_clock.elapsed());
+        self.animations.tick(platform, self.anim_clock.elapsed());
 
         self.game_clock.toggle(self.animations.should_block());
         if !self.game_clock.is_suspended() {
-            self.board.draw();
+            self.board.draw(platform, self.settings.mode.fade);
 
             let piece = self.bag.current();
             let drop_pos = self.board.find_drop_position(piece, &self.position, self.rotation);
 
-            piece.draw(&drop_pos, self.rotation, 0.4);
-            piece.draw(&self.position, self.rotation, 1.0);
+            piece.draw(platform, &drop_pos, self.rotation, 0.4);
+            piece.draw(platform, &self.position, self.rotation, 1.0);
         }
 
         if self.frame_index % 2 == 0 {
-            self.output_stats();
+            self.output_stats(platform);
+        }
+
+        if let Some(action_timer) = self.action_timer {
+            if self.real_clock.elapsed() - action_timer >= ACTION_TEXT_DURATION {
+                platform.html("top_bar", "");
+                self.action_timer = None;
+            }
         }
 
         None
     }
 }
 
-impl State for RunningState {
-    fn tick(&mut self, timestamp: f64, controller: &Controller) -> Option<Box<dyn State>> {
+fn is_dismissed(controller: &Controller) -> bool {
+    let is_start = controller.button_input.is_triggered(INPUT_GAME_START);
+    let is_stop = controller.button_input.is_triggered(INPUT_GAME_STOP);
+    let num_swipes = controller.touch_input.swipes_up(TOUCH_SWIPE_DISTANCE_THRESHOLD).count();
+
+    is_start || is_stop || num_swipes > 0
+}
+
+fn output_menu<P: Platform>(settings: &Settings, platform: &mut P) {
+    let option = {
+        if settings.mode.has_level_select {
+            let level = settings.mode.start_level(settings.scoring_system.create().as_ref());
+            format!("<span class = \"option\">&#9660; LEVEL {} &#9650;</span>", level)
+        } else {
+            "".to_string()
+        }
+    };
+
+    let text = format!(
+        r#"
+        <div class = "menu">
+            <span class = "title">Press SPACE or swipe up to start</span>
+            <span class = "mode">&#9664; {} &#9654;</span>
+            {}
+            <span class = "description">{}</span>
+        </div>
+        "#,
+        settings.mode.kind.name(),
+        option,
+        settings.mode.description(),
+    );
+
+    platform.html("top_bar", &text);
+}
+
+fn output_values<P: Platform>(platform: &mut P, id: &str, values: &[(&str, String)]) {
+    let text: String = values.iter()
+        .map(|(name, value)| {
+            format!(
+                r#"
+                <div>
+                    <span class = "name">{}</span>
+                    <span class = "value">{}</span>
+                </div>
+                "#,
+                name,
+                value,
+            )
+        })
+        .collect();
+
+    platform.html(id, &text);
+}
+
+fn action_text(spin: rotation::Spin, num_lines: usize) -> Option<String> {
+    let lines = match num_lines {
+        0 => "",
+        1 => " SINGLE",
+        2 => " DOUBLE",
+        3 => " TRIPLE",
+        _ => " TETRIS",
+    };
+
+    match spin {
+        rotation::Spin::Full => Some(format!("T-SPIN{}", lines)),
+        rotation::Spin::Mini => Some(format!("T-SPIN MINI{}", lines)),
+        rotation::Spin::None if num_lines >= 4 => Some("TETRIS".to_string()),
+        rotation::Spin::None => None,
+    }
+}
+
+fn piece_html(piece: &piece::Piece, class: &str) -> String {
+    let ((x1, y1), (x2, y2)) = piece.bounds(0);
+    let coords: Vec<_> = piece.iter_coords(0).collect();
+
+    let mut html = format!("<div class = \"{}\">", class);
+    for y in y1..=y2 {
+        html.push_str("<div class = \"row\">");
+        for x in x1..=x2 {
+            if coords.contains(&(x, y)) {
+                html.push_str(&format!("<span class = \"block\" style = \"background-color: #{:06x}\"></span>", piece.color.to_argb32()));
+            } else {
+                html.push_str("<span class = \"block\"></span>");
+            }
+        }
+        html.push_str("</div>");
+    }
+    html.push_str("</div>");
+
+    html
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn output_hold<P: Platform>(platform: &mut P, held: Option<&piece::Piece>, can_hold: bool) {
+    let class = if can_hold { "piece" } else { "piece used" };
+    let text = format!(
+        r#"
+        <div>
+            <span class = "name">HOLD</span>
+            {}
+        </div>
+        "#,
+        held.map(|x| { piece_html(x, class) }).unwrap_or_default(),
+    );
+
+    platform.html("hold", &text);
+}
+
+fn output_next<'a, P, I>(platform: &mut P, pieces: I)
+    where P: Platform,
+          I: IntoIterator<Item = &'a piece::Piece>
+{
+    let text = format!(
+        r#"
+        <div>
+            <span class = "name">NEXT</span>
+            {}
+        </div>
+        "#,
+        pieces.into_iter().map(|x| { piece_html(x, "piece") }).collect::<String>(),
+    );
+
+    platform.html("next", &text);
+}
+
+impl<P: Platform> State<P> for RunningState<P> {
+    fn tick(&mut self, timestamp: f64, controller: &Controller, _settings: &mut Settings, platform: &mut P) -> Option<Box<dyn State<P>>> {
         self.real_clock.update(timestamp);
         self.anim_clock.update(timestamp);
         self.game_clock.update(timestamp);
 
-        let new_state = self.update(controller);
+        let num_taps = controller.touch_input
+            .multi_finger_taps(2, TOUCH_TAP_DISTANCE_THRESHOLD, TOUCH_TAP_PERIOD_THRESHOLD)
+            .count();
+
+        if controller.button_input.is_triggered(INPUT_PAUSE) || num_taps > 0 {
+            self.set_paused(!self.is_paused, platform);
+        }
+
+        if self.is_paused {
+            if controller.button_input.is_triggered(INPUT_GAME_STOP) {
+                self.set_paused(false, platform);
+                return Some(self.game_over(platform));
+            }
+
+            return None;
+        }
+
+        let new_state = self.update(controller, platform);
         self.frame_index += 1;
 
         new_state
     }
+
+    fn pause(&mut self, platform: &mut P) {
+        self.set_paused(true, platform);
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        let mut writer = encoding::Writer::new();
+        writer.raw(SNAPSHOT_MAGIC);
+        writer.u8(SNAPSHOT_VERSION);
+        writer.varint(replay::RULESET as u64);
+        self.encode(&mut writer);
+
+        Some(writer.into_checksummed_bytes())
+    }
+
+    fn position(&self) -> Option<String> {
+        let active = (self.bag.current(), &self.position, self.rotation);
+        Some(self.board.to_fumen(self.bag.pieces(), Some(active), None))
+    }
+
+    fn settings(&self) -> Option<&Settings> {
+        Some(&self.settings)
+    }
 }
 
-impl Game {
-    fn new(board_width: usize, board_height: usize) -> Self {
+impl<P: Platform> Game<P> {
+    pub fn new(mut platform: P, board_width: usize, board_height: usize) -> Self {
+        // Everything random in a game is derived from its seed
+        let seed = (platform.random() * 4_294_967_296.0) as u32;
+
+        let settings = Settings::new(board_width, board_height, seed);
+        let state = Box::new(TitleState::new(&settings, &mut platform));
+
         Self {
             controller: Controller::new(),
-            state: Box::new(TitleState::new(board_width, board_height)),
+            settings,
+            platform,
+            state,
+            replay: None,
+            is_recording: false,
+            playback: None,
+
+            last_timestamp: 0.0,
         }
     }
 
-    fn key_handler(&mut self, key_code: i32, state: i32) {
-        if state != 0 {
-            self.controller.button_input.button_press((0, key_code as usize));
+    pub fn platform(&self) -> &P {
+        &self.platform
+    }
+
+    pub fn set_mode(&mut self, kind: mode::Kind) {
+        self.input(replay::Event::SetMode(kind.id()));
+    }
+
+    pub fn set_mode_goal(&mut self, value: u32) {
+        self.input(replay::Event::SetModeGoal(value));
+    }
+
+    // Overrides the current mode's randomizer until another mode is selected
+    pub fn set_randomizer(&mut self, kind: randomizer::Kind) {
+        self.input(replay::Event::SetRandomizer(kind.id()));
+    }
+
+    pub fn set_rotation_system(&mut self, kind: rotation::Kind) {
+        self.input(replay::Event::SetRotationSystem(kind.id()));
+    }
+
+    pub fn set_scoring_system(&mut self, kind: scoring::Kind) {
+        self.input(replay::Event::SetScoringSystem(kind.id()));
+    }
+
+    pub fn set_preview_length(&mut self, length: usize) {
+        self.input(replay::Event::SetPreviewLength(length as u32));
+    }
+
+    pub fn set_lock_delay(&mut self, duration: f64, max_resets: Option<u32>) {
+        self.input(replay::Event::SetLockDelay(duration, max_resets.map(|x| { x as i32 }).unwrap_or(-1)));
+    }
+
+    pub fn set_garbage(&mut self, num_holes: usize, messiness: f64) {
+        self.input(replay::Event::SetGarbage(num_holes as u32, messiness));
+    }
+
+    pub fn set_hidden_rows(&mut self, num_rows: usize) {
+        self.input(replay::Event::SetHiddenRows(num_rows as u32));
+    }
+
+    // Takes effect with the next game, an empty definition restores the
+    // rotation system's pieces
+    pub fn set_piece_set(&mut self, text: &str) {
+        self.input(replay::Event::SetPieceSet(text.to_string()));
+    }
+
+    pub fn key_handler(&mut self, key_code: i32, state: i32) {
+        self.input(replay::Event::Key(key_code, state));
+    }
+
+    pub fn touch_start_handler(&mut self, touch_id: i32, x: i32, y: i32) {
+        self.input(replay::Event::TouchStart(touch_id, x, y));
+    }
+
+    pub fn touch_end_handler(&mut self, touch_id: i32, x: i32, y: i32) {
+        self.input(replay::Event::TouchEnd(touch_id, x, y));
+    }
+
+    pub fn touch_cancel_handler(&mut self, touch_id: i32, x: i32, y: i32) {
+        self.input(replay::Event::TouchCancel(touch_id, x, y));
+    }
+
+    pub fn touch_move_handler(&mut self, touch_id: i32, x: i32, y: i32) {
+        self.input(replay::Event::TouchMove(touch_id, x, y));
+    }
+
+    pub fn pause(&mut self) {
+        self.input(replay::Event::Pause);
+    }
+
+    pub fn tick(&mut self, timestamp: f64) {
+        if self.playback.is_some() {
+            self.play_back(timestamp);
         } else {
-            self.controller.button_input.button_release((0, key_code as usize));
+            // Whole milliseconds keep replays compact
+            self.apply(replay::Event::Tick(timestamp.round()));
         }
     }
 
-    fn touch_start_handler(&mut self, touch_id: i32, x: i32, y: i32) {
-        self.controller.touch_input.touch_start(touch_id, x, y);
+    // Empty if there's nothing to replay
+    pub fn export_replay(&self) -> Vec<u8> {
+        self.replay.as_ref().map(|x| { x.to_bytes() }).unwrap_or_default()
     }
 
-    fn touch_end_handler(&mut self, touch_id: i32, x: i32, y: i32) {
-        self.controller.touch_input.touch_end(touch_id, x, y);
+    // Empty if no game is in progress
+    pub fn save_state(&self) -> Vec<u8> {
+        self.state.save().unwrap_or_default()
     }
 
-    fn touch_cancel_handler(&mut self, touch_id: i32, x: i32, y: i32) {
-        self.controller.touch_input.touch_cancel(touch_id, x, y);
+    // Fumen of the current position, or empty if no game is running
+    pub fn export_position(&self) -> String {
+        self.state.position().unwrap_or_default()
     }
 
-    fn touch_move_handler(&mut self, touch_id: i32, x: i32, y: i32) {
-        self.controller.touch_input.touch_move(touch_id, x, y);
+    // Starts an endless game from a fumen position, for practicing setups
+    pub fn load_position(&mut self, text: &str) {
+        self.input(replay::Event::LoadPosition(text.to_string()));
     }
 
-    fn tick(&mut self, timestamp: f64) {
-        if let Some(new_state) = self.state.tick(timestamp, &self.controller) {
-            self.state = new_state;
+    // Continues a game saved with `save_state`, paused
+    pub fn load_state(&mut self, bytes: &[u8]) {
+        let state = match self.restore(bytes) {
+            Ok(state) => state,
+            Err(message) => {
+                self.platform.log(&format!("Cannot load saved game: {}", message));
+                return;
+            },
+        };
+
+        // Move on to the next seed, as if the restored game was started here
+        self.settings = state.settings.clone();
+        self.settings.advance_seed();
+
+        self.controller = Controller::new();
+        self.state = Box::new(state);
+        self.replay = None;
+        self.is_recording = false;
+        self.playback = None;
+    }
+
+    fn restore(&mut self, bytes: &[u8]) -> Result<RunningState<P>, String> {
+        if !bytes.starts_with(SNAPSHOT_MAGIC) {
+            return Err("Not a saved game".to_string());
         }
 
-        self.controller.button_input.update(timestamp);
-        self.controller.touch_input.update(timestamp);
+        let data = encoding::verify_checksum(bytes).ok_or("Checksum mismatch, the saved game is damaged")?;
+        let mut reader = encoding::Reader::new(&data[SNAPSHOT_MAGIC.len()..]);
+
+        let version = reader.u8().ok_or("Truncated header")?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported saved game version {}", version));
+        }
+
+        let ruleset = reader.varint().ok_or("Truncated header")?;
+        if ruleset != replay::RULESET as u64 {
+            return Err(format!("Game was saved with ruleset {}, but this is ruleset {}", ruleset, replay::RULESET));
+        }
+
+        RunningState::restore(&mut reader, &mut self.platform).ok_or_else(|| { "Invalid game state".to_string() })
+    }
+
+    // Starts the recorded game over and plays back the replay, after which
+    // the player takes over.
+    pub fn load_replay(&mut self, bytes: &[u8]) {
+        let replay = match replay::Replay::from_bytes(bytes) {
+            Ok(replay) => replay,
+            Err(message) => {
+                self.platform.log(&format!("Cannot load replay: {}", message));
+                return;
+            },
+        };
+
+        let mut reader = encoding::Reader::new(&replay.settings);
+        let settings = match Settings::decode(&mut reader) {
+            Some(settings) => settings,
+            None => {
+                self.platform.log("Cannot load replay: Invalid settings");
+                return;
+            },
+        };
+
+        let page = match replay.position.as_str() {
+            "" => None,
+            text => match fumen::decode(text) {
+                Ok(page) => Some(page),
+                Err(message) => {
+                    self.platform.log(&format!("Cannot load replay: {}", message));
+                    return;
+                },
+            },
+        };
+
+        let mut state = Box::new(RunningState::new(settings.clone(), &mut self.platform));
+        let new_state = page.and_then(|page| { state.load_position(&page, &mut self.platform) });
+
+        self.settings = settings;
+        self.settings.advance_seed();
+
+        self.last_timestamp = replay.start_time;
+        self.switch_state(state, &replay.position);
+        if let Some(new_state) = new_state {
+            self.switch_state(new_state, "");
+        }
+
+        self.playback = Some(Playback {
+            events: replay.events,
+            index: 0,
+            time_offset: None,
+        });
+    }
+
+    // Replaces the current state. A game starting begins a new replay with
+    // fresh input, so that it doesn't depend on anything before it, and a
+    // game ending stops recording it.
+    fn switch_state(&mut self, state: Box<dyn State<P>>, position: &str) {
+        self.state = state;
+
+        let settings = self.state.settings().map(|settings| {
+            let mut writer = encoding::Writer::new();
+            settings.encode(&mut writer);
+            writer.into_bytes()
+        });
+
+        if let Some(settings) = settings {
+            self.replay = Some(replay::Replay::new(settings, position, self.last_timestamp));
+            self.is_recording = true;
+
+            self.controller = Controller::new();
+            self.controller.button_input.update(self.last_timestamp);
+            self.controller.touch_input.update(self.last_timestamp);
+        } else {
+            self.is_recording = false;
+        }
+    }
+
+    // Live input is ignored while a replay is played back
+    fn input(&mut self, event: replay::Event) {
+        if self.playback.is_none() {
+            self.apply(event);
+        }
+    }
+
+    // Applies all recorded events up to the recorded frame matching `timestamp`
+    fn play_back(&mut self, timestamp: f64) {
+        while let Some(playback) = &mut self.playback {
+            let event = match playback.events.get(playback.index) {
+                Some(event) => event.clone(),
+                None => {
+                    self.playback = None;
+                    break;
+                },
+            };
+
+            if let replay::Event::Tick(recorded) = event {
+                let time_offset = *playback.time_offset.get_or_insert(recorded - timestamp);
+                if recorded > timestamp + time_offset {
+                    break;
+                }
+            }
+
+            playback.index += 1;
+            self.apply(event);
+        }
+    }
+
+    fn apply(&mut self, event: replay::Event) {
+        if self.is_recording {
+            if let Some(replay) = &mut self.replay {
+                replay.push(event.clone());
+            }
+        }
+
+        match event {
+            replay::Event::Tick(timestamp) => {
+                self.last_timestamp = timestamp;
+                let new_state = self.state.tick(timestamp, &self.controller, &mut self.settings, &mut self.platform);
+
+                self.controller.button_input.update(timestamp);
+                self.controller.touch_input.update(timestamp);
+
+                if let Some(new_state) = new_state {
+                    self.switch_state(new_state, "");
+                }
+            },
+            replay::Event::Key(key_code, state) => {
+                if state != 0 {
+                    self.controller.button_input.button_press((0, key_code as usize));
+                } else {
+                    self.controller.button_input.button_release((0, key_code as usize));
+                }
+            },
+            replay::Event::TouchStart(touch_id, x, y) => self.controller.touch_input.touch_start(touch_id, x, y),
+            replay::Event::TouchEnd(touch_id, x, y) => self.controller.touch_input.touch_end(touch_id, x, y),
+            replay::Event::TouchCancel(touch_id, x, y) => self.controller.touch_input.touch_cancel(touch_id, x, y),
+            replay::Event::TouchMove(touch_id, x, y) => self.controller.touch_input.touch_move(touch_id, x, y),
+            replay::Event::Pause => self.state.pause(&mut self.platform),
+            replay::Event::SetMode(id) => {
+                if let Some(kind) = mode::Kind::from_id(id) {
+                    self.settings.mode = mode::Mode::new(kind);
+                    self.settings.mode.fit_to_board(self.settings.board_height);
+
+                    let state = Box::new(TitleState::new(&self.settings, &mut self.platform));
+                    self.switch_state(state, "");
+                }
+            },
+            replay::Event::SetModeGoal(value) => {
+                self.settings.mode.set_goal_value(value);
+                self.settings.mode.fit_to_board(self.settings.board_height);
+
+                let state = Box::new(TitleState::new(&self.settings, &mut self.platform));
+                self.switch_state(state, "");
+            },
+            replay::Event::SetRandomizer(id) => {
+                if let Some(kind) = randomizer::Kind::from_id(id) {
+                    self.settings.mode.randomizer = kind;
+                }
+            },
+            replay::Event::SetRotationSystem(id) => {
+                if let Some(kind) = rotation::Kind::from_id(id) {
+                    self.settings.rotation_system = kind;
+                }
+            },
+            replay::Event::SetScoringSystem(id) => {
+                if let Some(kind) = scoring::Kind::from_id(id) {
+                    self.settings.scoring_system = kind;
+                }
+            },
+            replay::Event::SetPreviewLength(length) => {
+                self.settings.preview_length = util::clamp(length as usize, 1, piece::MAX_PEEK);
+            },
+            replay::Event::SetLockDelay(duration, max_resets) => {
+                self.settings.lock_delay = LockDelay {
+                    duration: duration.max(0.0),
+                    reset: if max_resets >= 0 { LockReset::Move(max_resets as u32) } else { LockReset::Step },
+                };
+            },
+            replay::Event::SetGarbage(num_holes, messiness) => {
+                self.settings.garbage = Garbage {
+                    num_holes: (num_holes as usize).max(1),
+                    messiness: util::clamp(messiness, 0.0, 1.0),
+                };
+            },
+            replay::Event::SetHiddenRows(num_rows) => {
+                self.settings.num_hidden_rows = (num_rows as usize).min(self.settings.board_height);
+            },
+            replay::Event::LoadPosition(text) => {
+                let page = match fumen::decode(&text) {
+                    Ok(page) => page,
+                    Err(message) => {
+                        self.platform.log(&format!("Cannot load position: {}", message));
+                        return;
+                    },
+                };
+
+                let mut settings = self.settings.clone();
+                settings.mode = mode::Mode::new(mode::Kind::Endless);
+
+                let mut state = Box::new(RunningState::new(settings, &mut self.platform));
+                let new_state = state.load_position(&page, &mut self.platform);
+
+                self.switch_state(state, &text);
+                if let Some(new_state) = new_state {
+                    self.switch_state(new_state, "");
+                }
+
+                self.settings.advance_seed();
+            },
+            replay::Event::SetPieceSet(text) => {
+                if text.is_empty() {
+                    self.settings.piece_set = None;
+                } else {
+                    match piece::parse_piece_set(&text) {
+                        Ok(_) => self.settings.piece_set = Some(text),
+                        Err(message) => self.platform.log(&format!("Cannot load piece set: {}", message)),
+                    }
+                }
+            },
+        }
     }
 }
 
 #[no_mangle]
 pub extern fn Game_new(board_width: usize, board_height: usize) -> u32 {
-    wasm_glue::into_address(Game::new(board_width, board_height))
+    wasm_glue::into_address(JsGame::new(platform::JsPlatform, board_width, board_height))
 }
 
 #[no_mangle]
 pub extern fn Game_key_handler(address: u32, key_code: i32, state: i32) {
-    wasm_glue::with_address_as_mut(address, |o: &mut Game| { o.key_handler(key_code, state) })
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.key_handler(key_code, state) })
 }
 
 #[no_mangle]
 pub extern fn Game_touch_start_handler(address: u32, touch_id: i32, x: i32, y: i32) {
-    wasm_glue::with_address_as_mut(address, |o: &mut Game| { o.touch_start_handler(touch_id, x, y) })
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.touch_start_handler(touch_id, x, y) })
 }
 
 #[no_mangle]
 pub extern fn Game_touch_end_handler(address: u32, touch_id: i32, x: i32, y: i32) {
-    wasm_glue::with_address_as_mut(address, |o: &mut Game| { o.touch_end_handler(touch_id, x, y) })
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.touch_end_handler(touch_id, x, y) })
 }
 
 #[no_mangle]
 pub extern fn Game_touch_cancel_handler(address: u32, touch_id: i32, x: i32, y: i32) {
-    wasm_glue::with_address_as_mut(address, |o: &mut Game| { o.touch_cancel_handler(touch_id, x, y) })
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.touch_cancel_handler(touch_id, x, y) })
 }
 
 #[no_mangle]
 pub extern fn Game_touch_move_handler(address: u32, touch_id: i32, x: i32, y: i32) {
-    wasm_glue::with_address_as_mut(address, |o: &mut Game| { o.touch_move_handler(touch_id, x, y) })
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.touch_move_handler(touch_id, x, y) })
 }
 
 #[no_mangle]
 pub extern fn Game_tick(address: u32, timestamp: f64) {
-    wasm_glue::with_address_as_mut(address, |o: &mut Game| { o.tick(timestamp) })
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.tick(timestamp) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_mode(address: u32, mode: u32) {
+    if let Some(kind) = mode::Kind::from_id(mode) {
+        wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_mode(kind) })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_mode_goal(address: u32, value: u32) {
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_mode_goal(value) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_randomizer(address: u32, randomizer: u32) {
+    if let Some(kind) = randomizer::Kind::from_id(randomizer) {
+        wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_randomizer(kind) })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_rotation_system(address: u32, rotation_system: u32) {
+    if let Some(kind) = rotation::Kind::from_id(rotation_system) {
+        wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_rotation_system(kind) })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_scoring_system(address: u32, scoring_system: u32) {
+    if let Some(kind) = scoring::Kind::from_id(scoring_system) {
+        wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_scoring_system(kind) })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_preview_length(address: u32, length: u32) {
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_preview_length(length as usize) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_lock_delay(address: u32, duration: f64, max_resets: i32) {
+    let max_resets = if max_resets >= 0 { Some(max_resets as u32) } else { None };
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_lock_delay(duration, max_resets) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_garbage(address: u32, num_holes: u32, messiness: f64) {
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_garbage(num_holes as usize, messiness) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_hidden_rows(address: u32, num_rows: u32) {
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_hidden_rows(num_rows as usize) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_set_piece_set(address: u32) {
+    let text = unsafe { wasm_glue::stack_pop_string() };
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.set_piece_set(&text) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_export_position(address: u32) {
+    let text = wasm_glue::with_address_as_ref(address, |o: &JsGame| { o.export_position() });
+    unsafe { wasm_glue::stack_push_vec(text.into_bytes()) }
+}
+
+#[no_mangle]
+pub extern "C" fn Game_load_position(address: u32) {
+    let text = unsafe { wasm_glue::stack_pop_string() };
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.load_position(&text) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_pause(address: u32) {
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.pause() })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_export_replay(address: u32) {
+    let bytes = wasm_glue::with_address_as_ref(address, |o: &JsGame| { o.export_replay() });
+    unsafe { wasm_glue::stack_push_vec(bytes) }
+}
+
+#[no_mangle]
+pub extern "C" fn Game_load_replay(address: u32) {
+    let bytes = unsafe { wasm_glue::stack_pop_vec() };
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.load_replay(&bytes) })
+}
+
+#[no_mangle]
+pub extern "C" fn Game_save_state(address: u32) {
+    let bytes = wasm_glue::with_address_as_ref(address, |o: &JsGame| { o.save_state() });
+    unsafe { wasm_glue::stack_push_vec(bytes) }
+}
+
+#[no_mangle]
+pub extern "C" fn Game_load_state(address: u32) {
+    let bytes = unsafe { wasm_glue::stack_pop_vec() };
+    wasm_glue::with_address_as_mut(address, |o: &mut JsGame| { o.load_state(&bytes) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::RecordingPlatform;
+
+    const FRAME_DURATION: f64 = 16.0;
+
+    struct Harness {
+        game: Game<RecordingPlatform>,
+        timestamp: f64,
+    }
+
+    impl Harness {
+        fn new(seed: u32) -> Self {
+            let mut harness = Self { game: Game::new(RecordingPlatform::new(seed), 10, 20), timestamp: 1000.0 };
+            harness.run(1);
+
+            harness
+        }
+
+        fn run(&mut self, num_frames: usize) {
+            for _ in 0..num_frames {
+                self.game.tick(self.timestamp);
+                self.timestamp += FRAME_DURATION;
+            }
+        }
+
+        fn press(&mut self, input: (usize, usize)) {
+            self.game.key_handler(input.1 as i32, 1);
+            self.run(1);
+            self.game.key_handler(input.1 as i32, 0);
+            self.run(1);
+        }
+
+        fn start(&mut self) {
+            self.press(INPUT_GAME_START);
+            self.run(10);
+        }
+
+        fn hard_drop(&mut self) {
+            self.press(INPUT_HARD_DROP);
+
+            // Wait for the hard drop animation
+            self.run(15);
+        }
+
+        // Plays the same few moves regardless of the pieces dealt
+        fn play(&mut self, num_pieces: usize) {
+            let moves = [INPUT_MOVE_LEFT, INPUT_ROTATE_CW, INPUT_MOVE_RIGHT, INPUT_HOLD, INPUT_ROTATE_CCW];
+            for i in 0..num_pieces {
+                self.press(moves[i % moves.len()]);
+                self.press(moves[(i * 3 + 1) % moves.len()]);
+                self.hard_drop();
+            }
+        }
+
+        fn html(&self, id: &str) -> String {
+            self.game.platform().get_html(id).unwrap_or_default().split_whitespace().collect::<Vec<_>>().join(" ")
+        }
+
+        fn stat(&self, name: &str) -> String {
+            let prefix = format!("<span class = \"name\">{}</span> <span class = \"value\">", name);
+            let html = self.html("stats");
+            let start = html.find(&prefix).unwrap() + prefix.len();
+
+            html[start..start + html[start..].find('<').unwrap()].to_string()
+        }
+
+        // Cells of the board drawn in any color but black
+        fn num_lit_blocks(&self) -> usize {
+            (0..10).flat_map(|x| { (0..20).map(move |y| { (x, y) }) })
+                .filter(|&(x, y)| { self.game.platform().get_block(x, y) != 0 })
+                .count()
+        }
+
+        fn num_blocks(&self) -> usize {
+            let page = fumen::decode(&self.game.export_position()).unwrap();
+            page.field.iter().filter(|&&x| { x != 0 }).count()
+        }
+    }
+
+    #[test]
+    fn title_screen() {
+        let harness = Harness::new(1);
+        assert!(harness.html("top_bar").contains("Press SPACE or swipe up to start"));
+        assert!(harness.game.platform().num_draw_calls() > 0);
+        assert_eq!(harness.game.export_position(), "");
+    }
+
+    #[test]
+    fn hard_drops() {
+        let mut harness = Harness::new(2);
+        harness.start();
+        assert!(!harness.html("top_bar").contains("Press SPACE"));
+        assert_eq!(harness.num_blocks(), 0);
+
+        for num_pieces in 1..=4 {
+            harness.play(1);
+            assert_eq!(harness.num_blocks() % 4, 0);
+            assert!(harness.num_blocks() <= 4 * num_pieces);
+        }
+
+        // Guideline scoring awards two points per row of a hard drop
+        let score: u32 = harness.stat("SCORE").parse().unwrap();
+        assert!(score > 0);
+        assert_eq!(score % 2, 0);
+        assert_eq!(harness.stat("LEVEL"), "1");
+    }
+
+    #[test]
+    fn game_over() {
+        let mut harness = Harness::new(3);
+        harness.start();
+
+        for _ in 0..40 {
+            harness.hard_drop();
+        }
+
+        assert!(harness.html("top_bar").contains("GAME OVER"));
+        assert_eq!(harness.game.export_position(), "");
+
+        // The replay ends with the game
+        let replay = harness.game.export_replay();
+        harness.run(100);
+        assert_eq!(harness.game.export_replay(), replay);
+    }
+
+    #[test]
+    fn platform_seeds_game() {
+        let play = |seed| {
+            let mut harness = Harness::new(seed);
+            harness.start();
+            harness.play(8);
+            harness.game.export_position()
+        };
+
+        assert_eq!(play(4), play(4));
+        assert_ne!(play(4), play(5));
+    }
+
+    #[test]
+    fn hold_once_per_piece() {
+        let mut harness = Harness::new(6);
+        harness.start();
+        assert!(!harness.html("hold").contains("block"));
+
+        harness.press(INPUT_HOLD);
+        let position = harness.game.export_position();
+        assert!(harness.html("hold").contains("piece used"));
+
+        // Holding again has to wait for the next piece
+        harness.press(INPUT_HOLD);
+        assert_eq!(harness.game.export_position(), position);
+
+        harness.hard_drop();
+        assert!(harness.html("hold").contains("class = \"piece\""));
+        harness.press(INPUT_HOLD);
+        assert!(harness.html("hold").contains("piece used"));
+    }
+
+    #[test]
+    fn preview_length() {
+        let count_next = |length: Option<usize>| {
+            let mut harness = Harness::new(7);
+            if let Some(length) = length {
+                harness.game.set_preview_length(length);
+            }
+
+            harness.start();
+            harness.html("next").matches("class = \"piece\"").count()
+        };
+
+        assert_eq!(count_next(None), 5);
+        for &(length, expected) in &[(1, 1), (6, 6), (0, 1), (100, piece::MAX_PEEK)] {
+            assert_eq!(count_next(Some(length)), expected);
+        }
+    }
+
+    fn running_state(platform: &mut RecordingPlatform, configure: impl FnOnce(&mut Settings)) -> RunningState<RecordingPlatform> {
+        let mut settings = Settings::new(10, 20, 1);
+        configure(&mut settings);
+
+        let mut state = RunningState::new(settings, platform);
+        state.game_clock.update(0.0);
+
+        state
+    }
+
+    // Puts the current piece on the floor and starts its lock delay
+    fn touch_down(state: &mut RunningState<RecordingPlatform>, platform: &mut RecordingPlatform) {
+        state.position = state.board.find_drop_position(state.bag.current(), &state.position, state.rotation);
+        state.update_lowest_row();
+        assert!(state.apply_gravity(platform).is_none());
+        assert!(state.lock_timer.is_some());
+    }
+
+    // Advances the game clock to `time` and returns whether the piece locked
+    fn is_locked_at(state: &mut RunningState<RecordingPlatform>, platform: &mut RecordingPlatform, time: f64, offset: i32) -> bool {
+        let num_pieces = state.num_pieces;
+        state.game_clock.update(time);
+        if offset != 0 {
+            assert!(state.move_piece_x(offset));
+        }
+
+        state.apply_gravity(platform);
+        state.num_pieces > num_pieces
+    }
+
+    // Columns of the current piece's bottom row
+    fn bottom_columns(state: &RunningState<RecordingPlatform>) -> Vec<usize> {
+        let piece = state.bag.current();
+        let (_, (_, y2)) = piece.bounds(state.rotation);
+
+        piece.iter_coords(state.rotation)
+            .filter(|&(_, y)| { y == y2 })
+            .map(|(x, _)| { (state.position.x + x as i32) as usize })
+            .collect()
+    }
+
+    fn drop_and_lock(state: &mut RunningState<RecordingPlatform>, platform: &mut RecordingPlatform) -> Option<Box<dyn State<RecordingPlatform>>> {
+        state.position = state.board.find_drop_position(state.bag.current(), &state.position, state.rotation);
+        state.lock_piece(platform)
+    }
+
+    // Empties the board and fills the bottom row except below the current
+    // piece, then drops and locks the piece there to clear it
+    fn clear_line(state: &mut RunningState<RecordingPlatform>, platform: &mut RecordingPlatform) -> Option<Box<dyn State<RecordingPlatform>>> {
+        let holes = bottom_columns(state);
+        state.board.clear();
+        state.board.insert_garbage_row(&holes, &gfx::Color::gray());
+        drop_and_lock(state, platform)
+    }
+
+    fn with_lock_delay(duration: f64, reset: LockReset) -> impl FnOnce(&mut Settings) {
+        move |settings| { settings.lock_delay = LockDelay { duration, reset } }
+    }
+
+    #[test]
+    fn lock_delay() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, with_lock_delay(500.0, LockReset::Move(15)));
+
+        touch_down(&mut state, &mut platform);
+        assert!(!is_locked_at(&mut state, &mut platform, 499.0, 0));
+        assert!(is_locked_at(&mut state, &mut platform, 500.0, 0));
+    }
+
+    #[test]
+    fn lock_delay_move_reset() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, with_lock_delay(500.0, LockReset::Move(2)));
+
+        // Each move restarts the delay, up to twice
+        touch_down(&mut state, &mut platform);
+        assert!(!is_locked_at(&mut state, &mut platform, 400.0, 1));
+        assert!(!is_locked_at(&mut state, &mut platform, 800.0, -1));
+        assert!(!is_locked_at(&mut state, &mut platform, 1299.0, 0));
+
+        // The third move is refused and locks the piece
+        assert!(is_locked_at(&mut state, &mut platform, 1299.0, 1));
+    }
+
+    #[test]
+    fn lock_delay_without_resets() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, with_lock_delay(500.0, LockReset::Move(0)));
+
+        // The full delay still applies as long as the piece isn't moved
+        touch_down(&mut state, &mut platform);
+        assert!(!is_locked_at(&mut state, &mut platform, 499.0, 0));
+        assert!(is_locked_at(&mut state, &mut platform, 500.0, 0));
+
+        let mut state = running_state(&mut platform, with_lock_delay(500.0, LockReset::Move(0)));
+        touch_down(&mut state, &mut platform);
+        assert!(is_locked_at(&mut state, &mut platform, 100.0, 1));
+    }
+
+    #[test]
+    fn lock_delay_step_reset() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, with_lock_delay(500.0, LockReset::Step));
+
+        // Moving doesn't restart the delay
+        touch_down(&mut state, &mut platform);
+        assert!(!is_locked_at(&mut state, &mut platform, 200.0, 1));
+        assert!(!is_locked_at(&mut state, &mut platform, 400.0, -1));
+        assert!(is_locked_at(&mut state, &mut platform, 500.0, 1));
+
+        // Falling to a new lowest row does, here off a ledge on the left
+        let mut state = running_state(&mut platform, with_lock_delay(500.0, LockReset::Step));
+        state.board.insert_garbage_row(&[5, 6, 7, 8, 9], &gfx::Color::gray());
+        touch_down(&mut state, &mut platform);
+
+        state.game_clock.update(200.0);
+        state.move_piece_x(10);
+        assert!(state.move_piece_y(1));
+        assert!(state.apply_gravity(&mut platform).is_none());
+        assert!(!is_locked_at(&mut state, &mut platform, 699.0, 0));
+        assert!(is_locked_at(&mut state, &mut platform, 700.0, 0));
+    }
+
+    #[test]
+    fn sprint() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |settings| { settings.mode = mode::Mode::new(mode::Kind::Sprint) });
+        state.real_clock.update(0.0);
+
+        // Gravity stays at the first level
+        state.num_cleared_lines = 38;
+        assert!(clear_line(&mut state, &mut platform).is_none());
+        assert_eq!((state.num_cleared_lines, state.level), (39, 1));
+
+        // The last line ends the game with the time to the centisecond
+        state.real_clock.update(83_456.0);
+        assert!(clear_line(&mut state, &mut platform).is_some());
+        assert!(platform.get_html("top_bar").unwrap().contains("CLEAR!"));
+        assert!(platform.get_html("stats").unwrap().contains("01:23.45"));
+    }
+
+    #[test]
+    fn ultra() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |settings| { settings.mode = mode::Mode::new(mode::Kind::Ultra) });
+        let controller = Controller::new();
+
+        // The timer counts down
+        state.real_clock.update(0.0);
+        state.real_clock.update(30_000.0);
+        state.output_stats(&mut platform);
+        assert!(platform.get_html("stats").unwrap().contains("01:30.00"));
+
+        state.real_clock.update(119_999.0);
+        assert!(state.update(&controller, &mut platform).is_none());
+
+        state.real_clock.update(120_500.0);
+        assert!(state.update(&controller, &mut platform).is_some());
+        assert!(platform.get_html("top_bar").unwrap().contains("TIME UP!"));
+        assert!(platform.get_html("stats").unwrap().contains("02:00.00"));
+    }
+
+    #[test]
+    fn marathon() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |settings| {
+            settings.mode = mode::Mode::new(mode::Kind::Marathon);
+            settings.mode.start_level = Some(5);
+            settings.mode.set_goal_value(12);
+        });
+        assert_eq!(state.level, 5);
+
+        // Levels count up from the start level
+        state.num_cleared_lines = 9;
+        assert!(clear_line(&mut state, &mut platform).is_none());
+        assert_eq!(state.level, 6);
+        assert!(clear_line(&mut state, &mut platform).is_none());
+        assert_eq!(state.level, 6);
+
+        assert!(clear_line(&mut state, &mut platform).is_some());
+        assert!(platform.get_html("top_bar").unwrap().contains("CLEAR!"));
+    }
+
+    #[test]
+    fn zen_top_out() {
+        let fill = |state: &mut RunningState<RecordingPlatform>| {
+            for _ in 0..(state.board.height() + state.board.num_hidden_rows()) {
+                state.board.insert_garbage_row(&[0], &gfx::Color::gray());
+            }
+        };
+
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |settings| { settings.mode = mode::Mode::new(mode::Kind::Zen) });
+        fill(&mut state);
+        assert!(!state.spawn_piece());
+
+        // The top half of the stack is cleared and the game goes on
+        assert!(state.top_out(&mut platform).is_none());
+        assert_eq!(state.num_penalties, 1);
+        assert!(platform.get_html("top_bar").unwrap().contains("PENALTY"));
+        assert!(state.board.iter_blocks().all(|(_, y, _)| { y >= 10 }));
+        assert_eq!(state.board.iter_blocks().count(), 10 * 9);
+
+        let mut state = running_state(&mut platform, |_| {});
+        fill(&mut state);
+        assert!(state.top_out(&mut platform).is_some());
+        assert!(platform.get_html("top_bar").unwrap().contains("GAME OVER"));
+    }
+
+    #[test]
+    fn dig() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |settings| {
+            settings.mode = mode::Mode::new(mode::Kind::Dig);
+            settings.mode.set_goal_value(3);
+        });
+
+        assert_eq!(state.num_garbage_rows, 3);
+        assert_eq!(state.board.iter_blocks().count(), 3 * 9);
+        assert!(state.board.iter_blocks().all(|(_, y, _)| { y >= 17 }));
+
+        // Lines cleared above the garbage don't count
+        state.board.clear();
+        state.num_garbage_rows = 1;
+        state.board.insert_garbage_row(&bottom_columns(&state), &gfx::Color::gray());
+        state.board.insert_garbage_row(&[0], &gfx::Color::gray());
+        assert!(drop_and_lock(&mut state, &mut platform).is_none());
+        assert_eq!((state.num_cleared_lines, state.num_garbage_rows), (1, 1));
+
+        assert!(clear_line(&mut state, &mut platform).is_some());
+        assert_eq!(state.num_garbage_rows, 0);
+        assert!(platform.get_html("top_bar").unwrap().contains("CLEAR!"));
+    }
+
+    #[test]
+    fn rising_garbage() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |settings| { settings.mode = mode::Mode::new(mode::Kind::Survival) });
+
+        state.game_clock.update(7999.0);
+        assert!(state.apply_rising_garbage(&mut platform).is_none());
+        assert_eq!(state.board.iter_blocks().count(), 0);
+
+        state.game_clock.update(8000.0);
+        assert!(state.apply_rising_garbage(&mut platform).is_none());
+        assert_eq!(state.num_risen_rows, 1);
+        assert!(state.board.iter_blocks().all(|(_, y, _)| { y == 19 }));
+
+        // A piece in the way is pushed up along with the stack
+        state.position = state.board.find_drop_position(state.bag.current(), &state.position, state.rotation);
+        let position = state.position;
+        state.game_clock.update(15_599.0);
+        assert!(state.apply_rising_garbage(&mut platform).is_none());
+        assert_eq!(state.position, position);
+        state.game_clock.update(15_600.0);
+        assert!(state.apply_rising_garbage(&mut platform).is_none());
+        assert_eq!((state.num_risen_rows, state.position), (2, position.add_y(-1)));
+
+        // The game ends once garbage is pushed off the top
+        for _ in 0..(state.board.height() + state.board.num_hidden_rows()) {
+            state.board.insert_garbage_row(&[0], &gfx::Color::gray());
+        }
+
+        state.game_clock.update(30_000.0);
+        assert!(state.apply_rising_garbage(&mut platform).is_some());
+        assert!(platform.get_html("top_bar").unwrap().contains("GAME OVER"));
+        assert!(platform.get_html("stats").unwrap().contains("PIECES"));
+    }
+
+    #[test]
+    fn section_levels() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |settings| { settings.mode = mode::Mode::new(mode::Kind::Master) });
+        assert_eq!(state.level, 0);
+
+        // Pieces stop at the end of a section, lines don't
+        state.level = 98;
+        state.advance_section_level(1, false);
+        assert_eq!(state.level, 99);
+        state.advance_section_level(1, false);
+        assert_eq!(state.level, 99);
+        state.advance_section_level(2, true);
+        assert_eq!(state.level, 101);
+
+        // The level before the goal only gives way to a line clear
+        state.level = 997;
+        state.advance_section_level(1, false);
+        assert_eq!(state.level, 998);
+        state.advance_section_level(1, false);
+        assert_eq!(state.level, 998);
+        state.advance_section_level(4, true);
+        assert_eq!(state.level, 999);
+
+        state.level = 998;
+        assert!(clear_line(&mut state, &mut platform).is_some());
+        assert!(platform.get_html("top_bar").unwrap().contains("CLEAR!"));
+    }
+
+    #[test]
+    fn instant_gravity() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |settings| { settings.mode = mode::Mode::new(mode::Kind::Master) });
+
+        state.level = 499;
+        assert!(state.spawn_piece());
+        assert!(state.position.y < 0);
+
+        // Pieces spawn on the floor at 20G
+        state.level = 500;
+        assert!(state.spawn_piece());
+        let floor = state.board.find_drop_position(state.bag.current(), &state.position, state.rotation);
+        assert_eq!(state.position, floor);
+        assert!(state.position.y > 15);
+    }
+
+    #[test]
+    fn invisible() {
+        let mut harness = Harness::new(8);
+        harness.game.set_mode(mode::Kind::Invisible);
+        harness.press(INPUT_MENU_NEXT_OPTION);
+        assert!(harness.html("top_bar").contains("BLOCKS VANISH ON LOCK"));
+        harness.start();
+
+        // Only the falling piece and its ghost are left in view
+        harness.play(4);
+        harness.run(30);
+        assert_eq!(harness.num_blocks(), 4 * 4);
+        assert!(harness.num_lit_blocks() <= 2 * 4);
+
+        // The stack is revealed when the game ends
+        for _ in 0..40 {
+            if harness.html("top_bar").contains("GAME OVER") {
+                break;
+            }
+
+            harness.hard_drop();
+        }
+
+        assert!(harness.html("top_bar").contains("GAME OVER"));
+        harness.run((ANIMATION_DURATION_REVEAL / FRAME_DURATION) as usize);
+        assert!(harness.num_lit_blocks() > 4 * 4);
+    }
+
+    #[test]
+    fn pause_hides_pieces() {
+        let mut harness = Harness::new(10);
+        harness.start();
+        harness.press(INPUT_HOLD);
+        assert!(harness.html("hold").contains("block"));
+        assert!(harness.html("next").contains("block"));
+
+        harness.press(INPUT_PAUSE);
+        assert!(harness.html("overlay").contains("PAUSED"));
+        assert!(!harness.html("hold").contains("block"));
+        assert!(!harness.html("next").contains("block"));
+
+        // Nothing moves while paused
+        let position = harness.game.export_position();
+        harness.press(INPUT_HARD_DROP);
+        harness.run(100);
+        assert_eq!(harness.game.export_position(), position);
+
+        harness.press(INPUT_PAUSE);
+        assert_eq!(harness.html("overlay"), "");
+        assert!(harness.html("hold").contains("block"));
+        assert!(harness.html("next").contains("block"));
+    }
+
+    #[test]
+    fn replay_round_trip() {
+        let mut harness = Harness::new(4);
+        harness.game.set_randomizer(randomizer::Kind::Tgm);
+        harness.start();
+        harness.play(12);
+
+        let position = harness.game.export_position();
+        let stats = harness.html("stats");
+        let replay = harness.game.export_replay();
+        assert!(!replay.is_empty());
+
+        // Played back by a game with a different seed, at another time
+        let mut playback = Harness::new(5);
+        playback.timestamp += 12345.0;
+        playback.game.load_replay(&replay);
+        while playback.game.playback.is_some() {
+            playback.run(1);
+        }
+
+        assert_eq!(playback.game.export_position(), position);
+        assert_eq!(playback.html("stats"), stats);
+
+        // After playing back, the replay starts over with the same game
+        assert_eq!(replay::Replay::from_bytes(&playback.game.export_replay()).unwrap().settings,
+                   replay::Replay::from_bytes(&replay).unwrap().settings);
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut harness = Harness::new(6);
+        harness.game.set_mode(mode::Kind::Dig);
+        harness.game.set_mode_goal(3);
+        harness.game.set_scoring_system(scoring::Kind::Nes);
+        harness.start();
+        harness.play(6);
+        harness.press(INPUT_HOLD);
+
+        let position = harness.game.export_position();
+        let bytes = harness.game.save_state();
+        assert!(bytes.starts_with(SNAPSHOT_MAGIC));
+
+        let mut restored = Harness::new(7);
+        restored.game.load_state(&bytes);
+        assert!(restored.game.platform().messages().is_empty());
+        assert_eq!(restored.game.export_position(), position);
+
+        // Restored games start out paused
+        restored.run(5);
+        assert_eq!(restored.game.export_position(), position);
+        assert!(restored.html("overlay").contains("PAUSED"));
+
+        restored.press(INPUT_PAUSE);
+        restored.play(2);
+        assert_ne!(restored.game.export_position(), position);
+    }
+
+    #[test]
+    fn invalid_snapshots() {
+        let mut harness = Harness::new(8);
+        harness.start();
+        harness.play(3);
+        let bytes = harness.game.save_state();
+
+        let mut damaged = bytes.clone();
+        damaged[20] ^= 1;
+
+        let mut truncated = bytes[..bytes.len() / 2].to_vec();
+        let checksum = encoding::checksum(&truncated);
+        truncated.extend_from_slice(&checksum.to_le_bytes());
+
+        let cases: [(&[u8], &str); 4] = [
+            (&[], "Not a saved game"),
+            (&bytes[4..], "Not a saved game"),
+            (&damaged, "Checksum mismatch, the saved game is damaged"),
+            (&truncated, "Invalid game state"),
+        ];
+
+        for &(bytes, message) in cases.iter() {
+            let mut harness = Harness::new(9);
+            harness.game.load_state(bytes);
+
+            assert_eq!(harness.game.platform().messages().last().map(|x| { x.as_str() }), Some(&*format!("Cannot load saved game: {}", message)));
+            assert_eq!(harness.game.export_position(), "");
+        }
+    }
+
+    #[test]
+    fn snapshot_levels() {
+        let restore = |state: &RunningState<RecordingPlatform>, platform: &mut RecordingPlatform| {
+            let mut writer = encoding::Writer::new();
+            state.encode(&mut writer);
+            let bytes = writer.into_bytes();
+            RunningState::restore(&mut encoding::Reader::new(&bytes), platform).map(|x| { (x.start_level, x.level) })
+        };
+
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |_| {});
+        state.level = 20;
+        assert_eq!(restore(&state, &mut platform), Some((1, 20)));
+
+        // Levels beyond the scoring system's are refused
+        state.level = 21;
+        assert_eq!(restore(&state, &mut platform), None);
+        state.level = 5;
+        state.start_level = 0;
+        assert_eq!(restore(&state, &mut platform), None);
+
+        // Section levels go from 0 up to the goal
+        let mut state = running_state(&mut platform, |settings| { settings.mode = mode::Mode::new(mode::Kind::Master) });
+        state.level = 999;
+        assert_eq!(restore(&state, &mut platform), Some((0, 999)));
+        state.level = 1000;
+        assert_eq!(restore(&state, &mut platform), None);
+    }
+
+    #[test]
+    fn block_out() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |_| {});
+        assert!(!state.board.is_hidden(state.bag.current(), &state.position, state.rotation));
+
+        // The piece locks in view, but the next one has no room to spawn
+        let next = state.bag.peek(1).next().unwrap();
+        state.board.put_piece(next, &state.board.initial_position(next, 0), 0);
+        state.position = util::Position::new(0, state.position.y);
+
+        assert!(drop_and_lock(&mut state, &mut platform).is_some());
+        assert!(platform.get_html("top_bar").unwrap().contains("GAME OVER"));
+    }
+
+    #[test]
+    fn lock_out() {
+        let mut platform = RecordingPlatform::new(1);
+        let mut state = running_state(&mut platform, |_| {});
+        for _ in 0..state.board.height() {
+            state.board.insert_garbage_row(&[0], &gfx::Color::gray());
+        }
+
+        // The piece locks in the hidden rows, away from where the next one spawns
+        let position = state.board.initial_position(state.bag.current(), state.rotation);
+        state.position = util::Position::new(0, position.y);
+        assert!(state.board.is_hidden(state.bag.current(), &state.position, state.rotation));
+        assert!(drop_and_lock(&mut state, &mut platform).is_some());
+        assert!(platform.get_html("top_bar").unwrap().contains("GAME OVER"));
+
+        // As long as part of it is in view, the game goes on
+        let mut state = running_state(&mut platform, |_| {});
+        for _ in 1..state.board.height() {
+            state.board.insert_garbage_row(&[0], &gfx::Color::gray());
+        }
+
+        state.position = util::Position::new(0, position.y);
+        assert!(drop_and_lock(&mut state, &mut platform).is_none());
+    }
+
+    #[test]
+    fn load_position() {
+        // Bottom four rows full except for the last column
+        let mut field = vec![0; fumen::NUM_BLOCKS];
+        for row in fumen::HEIGHT - 4..fumen::HEIGHT {
+            for x in 0..fumen::WIDTH - 1 {
+                field[row * fumen::WIDTH + x] = fumen::GARBAGE;
+            }
+        }
+
+        let text = fumen::encode(&fumen::Page { field, piece: None, comment: None });
+
+        let mut harness = Harness::new(11);
+        harness.game.load_position(&text);
+        harness.run(10);
+        assert_eq!(harness.num_blocks(), 36);
+
+        harness.game.load_position("not a fumen");
+        assert_eq!(harness.game.platform().messages().last().map(|x| { x.as_str() }), Some("Cannot load position: Not a fumen v115 string"));
+        assert_eq!(harness.num_blocks(), 36);
+    }
+
+    #[test]
+    fn load_position_block_out() {
+        // A stack reaching the top leaves no room to spawn
+        let mut field = vec![0; fumen::NUM_BLOCKS];
+        for (i, block) in field.iter_mut().enumerate().take(fumen::HEIGHT * fumen::WIDTH) {
+            if i % fumen::WIDTH != i / fumen::WIDTH % fumen::WIDTH {
+                *block = fumen::GARBAGE;
+            }
+        }
+
+        let mut harness = Harness::new(12);
+        harness.game.load_position(&fumen::encode(&fumen::Page { field, piece: None, comment: None }));
+        harness.run(10);
+
+        assert!(harness.html("top_bar").contains("GAME OVER"));
+        assert_eq!(harness.game.export_position(), "");
+    }
 }
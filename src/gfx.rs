@@ -1,10 +1,11 @@
-use crate::{piece, util, js_api};
+use crate::{piece, util};
+use crate::platform::{Platform};
 
 mod ease;
 
-pub trait Animation {
+pub trait Animation<P: Platform> {
     fn should_block(&self) -> bool;
-    fn draw(&self, t: f64);
+    fn draw(&self, platform: &mut P, t: f64);
 }
 
 #[derive(Clone)]
@@ -14,8 +15,10 @@ pub struct Color {
     b: u8,
 }
 
-pub struct AnimationQueue {
-    animations: Vec<(f64, Option<f64>, Box<dyn Animation>)>,
+type ScheduledAnimation<P> = (f64, Option<f64>, Box<dyn Animation<P>>);
+
+pub struct AnimationQueue<P: Platform> {
+    animations: Vec<ScheduledAnimation<P>>,
 }
 
 pub struct LineClearAnimation {
@@ -39,6 +42,15 @@ pub struct TitleAnimation {
     pieces: Vec<piece::Piece>,
 }
 
+// Reveals the given blocks row by row, from the bottom up
+pub struct RevealAnimation {
+    blocks: Vec<(usize, usize, Color)>,
+    height: usize,
+}
+
+// Blocks the game for its duration without drawing anything
+pub struct DelayAnimation;
+
 pub struct GameOverAnimation {
     width: usize,
     height: usize,
@@ -53,6 +65,10 @@ impl Color {
         Self::rgb(255, 255, 255)
     }
 
+    pub fn gray() -> Self {
+        Self::rgb(128, 128, 128)
+    }
+
     pub fn rgb(r: u8, g: u8, b: u8) -> Self {
         Self {
             r: r,
@@ -94,7 +110,7 @@ impl Color {
     }
 }
 
-impl AnimationQueue {
+impl<P: Platform> AnimationQueue<P> {
     pub fn new() -> Self {
         Self {
             animations: Vec::new(),
@@ -109,15 +125,15 @@ impl AnimationQueue {
         self.animations.iter().any(|(_start, _end, anim)| { anim.should_block() })
     }
 
-    pub fn schedule(&mut self, start: f64, duration: f64, anim: Box<dyn Animation>) {
+    pub fn schedule(&mut self, start: f64, duration: f64, anim: Box<dyn Animation<P>>) {
         self.animations.push((start, Some(start + duration), anim));
     }
 
-    pub fn endless(&mut self, anim: Box<dyn Animation>) {
+    pub fn endless(&mut self, anim: Box<dyn Animation<P>>) {
         self.animations.push((0.0, None, anim));
     }
 
-    pub fn tick(&mut self, timestamp: f64) {
+    pub fn tick(&mut self, platform: &mut P, timestamp: f64) {
         if self.animations.is_empty() {
             return;
         }
@@ -126,7 +142,7 @@ impl AnimationQueue {
             if timestamp >= *start {
                 let divisor = maybe_end.map(|end| { end - start }).unwrap_or(1000.0);
                 let t = (timestamp - start) / divisor;
-                anim.draw(t);
+                anim.draw(platform, t);
             }
         }
 
@@ -136,6 +152,42 @@ impl AnimationQueue {
     }
 }
 
+impl RevealAnimation {
+    pub fn new(blocks: Vec<(usize, usize, Color)>, height: usize) -> Self {
+        Self {
+            blocks,
+            height,
+        }
+    }
+}
+
+impl<P: Platform> Animation<P> for RevealAnimation {
+    fn should_block(&self) -> bool {
+        false
+    }
+
+    fn draw(&self, platform: &mut P, t: f64) {
+        let num_rows = ease::quadratic_out(util::clamp(t, 0.0, 1.0)) * self.height as f64;
+
+        for (x, y, color) in self.blocks.iter() {
+            let row = (self.height - 1 - y) as f64;
+            let intensity = util::clamp(num_rows - row, 0.0, 1.0);
+
+            if intensity > 0.0 {
+                platform.draw_block(*x as u32, *y as u32, color.fade(intensity).to_argb32());
+            }
+        }
+    }
+}
+
+impl<P: Platform> Animation<P> for DelayAnimation {
+    fn should_block(&self) -> bool {
+        true
+    }
+
+    fn draw(&self, _platform: &mut P, _t: f64) {}
+}
+
 impl LineClearAnimation {
     pub fn new(rows: Vec<usize>, width: usize) -> Self {
         Self {
@@ -145,12 +197,12 @@ impl LineClearAnimation {
     }
 }
 
-impl Animation for LineClearAnimation {
+impl<P: Platform> Animation<P> for LineClearAnimation {
     fn should_block(&self) -> bool {
         true
     }
 
-    fn draw(&self, t: f64) {
+    fn draw(&self, platform: &mut P, t: f64) {
         let t = util::clamp(t, 0.0, 1.0);
 
         let color = {
@@ -163,7 +215,7 @@ impl Animation for LineClearAnimation {
 
         for &y in self.rows.iter() {
             for x in 0..self.width {
-                js_api::draw_block(x as u32, y as u32, color);
+                platform.draw_block(x as u32, y as u32, color);
             }
         }
     }
@@ -182,21 +234,24 @@ impl WhooshAnimation {
         }
     }
 
-    fn draw_points(&self, y: i32, color: &Color) {
+    fn draw_points<P: Platform>(&self, platform: &mut P, y: i32, color: &Color) {
         for &(bx, by) in self.points.iter() {
             let bx = bx as i32 + self.x;
             let by = by as i32 + y;
-            js_api::draw_block(bx as u32, by as u32, color.to_argb32());
+
+            if by >= 0 {
+                platform.draw_block(bx as u32, by as u32, color.to_argb32());
+            }
         }
     }
 }
 
-impl Animation for WhooshAnimation {
+impl<P: Platform> Animation<P> for WhooshAnimation {
     fn should_block(&self) -> bool {
         true
     }
 
-    fn draw(&self, t: f64) {
+    fn draw(&self, platform: &mut P, t: f64) {
         let t = util::clamp(t, 0.0, 1.0);
 
         for y in self.y1..self.y2 {
@@ -210,10 +265,10 @@ impl Animation for WhooshAnimation {
             };
 
             let color = self.color.fade(intensity);
-            self.draw_points(y, &color);
+            self.draw_points(platform, y, &color);
         }
 
-        self.draw_points(self.y2, &self.color);
+        self.draw_points(platform, self.y2, &self.color);
     }
 }
 
@@ -227,34 +282,34 @@ impl TitleAnimation {
         }
     }
 
-    fn draw_piece(&self, rng: &util::LinearCongruentialGenerator) {
-        let index = (rng.next() as usize) % self.pieces.len();
-        let rotation = (rng.next() as usize) % 4;
+    fn draw_piece<P: Platform>(&self, platform: &mut P, rng: &mut util::Pcg32) {
+        let index = rng.next_below(self.pieces.len() as u32) as usize;
+        let rotation = rng.next_below(4) as usize;
         let piece = &self.pieces[index];
         let ((x1, y1), (x2, y2)) = piece.bounds(rotation);
 
-        let cx = rng.next() as usize % (self.width - (x2 - x1)) - x1;
-        let cy = rng.next() as usize % (self.height - (y2 - y1)) - y1;
+        let cx = rng.next_below((self.width - (x2 - x1)) as u32) as i32 - x1 as i32;
+        let cy = rng.next_below((self.height - (y2 - y1)) as u32) as i32 - y1 as i32;
 
         for (bx, by) in piece.iter_coords(rotation) {
-            let bx = bx as i32 + cx as i32;
-            let by = by as i32 + cy as i32;
-            js_api::draw_block(bx as u32, by as u32, piece.color.to_argb32());
+            let bx = bx as i32 + cx;
+            let by = by as i32 + cy;
+            platform.draw_block(bx as u32, by as u32, piece.color.to_argb32());
         }
     }
 }
 
-impl Animation for TitleAnimation {
+impl<P: Platform> Animation<P> for TitleAnimation {
     fn should_block(&self) -> bool {
         false
     }
 
-    fn draw(&self, t: f64) {
+    fn draw(&self, platform: &mut P, t: f64) {
         let t = t * 2.0;
 
         for y in 0..self.height {
             for x in 0..self.width {
-                js_api::draw_block(x as u32, y as u32, 0x000000);
+                platform.draw_block(x as u32, y as u32, 0x000000);
             }
         }
 
@@ -262,8 +317,8 @@ impl Animation for TitleAnimation {
             return;
         }
 
-        let rng = util::LinearCongruentialGenerator::new(t.floor() as u32);
-        self.draw_piece(&rng);
+        let mut rng = util::Pcg32::new(t.floor() as u32);
+        self.draw_piece(platform, &mut rng);
     }
 }
 
@@ -276,12 +331,12 @@ impl GameOverAnimation {
     }
 }
 
-impl Animation for GameOverAnimation {
+impl<P: Platform> Animation<P> for GameOverAnimation {
     fn should_block(&self) -> bool {
         false
     }
 
-    fn draw(&self, t: f64) {
+    fn draw(&self, platform: &mut P, t: f64) {
         let white = Color::white();
 
         let t = 1.0 - util::clamp(t, 0.0, 1.0);
@@ -301,7 +356,7 @@ impl Animation for GameOverAnimation {
             };
 
             for x in 0..self.width {
-                js_api::draw_block(x as u32, y as u32, color);
+                platform.draw_block(x as u32, y as u32, color);
             }
         }
     }
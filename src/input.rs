@@ -22,6 +22,12 @@ pub struct TouchInput {
 
     active_curr: HashMap<i32, (Touch, Touch)>,
     active_prev: HashMap<i32, (Touch, Touch)>,
+
+    // Touches since the screen was last untouched. Touches of gestures with
+    // more than one finger are only reported once all fingers are lifted.
+    gesture: Vec<(Touch, Touch)>,
+    num_gesture_touches: usize,
+    finished_gestures: Vec<Vec<(Touch, Touch)>>,
 }
 
 impl ButtonInput {
@@ -107,6 +113,10 @@ impl TouchInput {
 
             active_curr: HashMap::new(),
             active_prev: HashMap::new(),
+
+            gesture: Vec::new(),
+            num_gesture_touches: 0,
+            finished_gestures: Vec::new(),
         }
     }
 
@@ -126,22 +136,43 @@ impl TouchInput {
         distance < max_distance && period < max_period
     }
 
+    fn is_long_press(start: &Touch, end: &Touch, max_distance: f64, min_period: f64) -> bool {
+        let distance = Self::get_distance(start, end);
+        let period = end.timestamp - start.timestamp;
+        distance < max_distance && period >= min_period
+    }
+
     pub fn update(&mut self, timestamp: f64) {
         self.timestamp = timestamp;
         self.finished.clear();
+        self.finished_gestures.clear();
 
         self.active_prev = self.active_curr.clone();
     }
 
     pub fn touch_start(&mut self, touch_id: i32, x: i32, y: i32) {
+        if self.active_curr.is_empty() {
+            self.gesture.clear();
+            self.num_gesture_touches = 0;
+        }
+
         let touch = Touch::new(util::Position::new(x, y), self.timestamp);
         self.active_curr.insert(touch_id, (touch.clone(), touch));
+        self.num_gesture_touches = self.num_gesture_touches.max(self.active_curr.len());
     }
 
     pub fn touch_end(&mut self, touch_id: i32, x: i32, y: i32) {
         if let Some((start, _)) = self.active_curr.remove(&touch_id) {
             let end = Touch::new(util::Position::new(x, y), self.timestamp);
-            self.finished.insert(touch_id, (start, end));
+
+            if self.num_gesture_touches > 1 {
+                self.gesture.push((start, end));
+                if self.active_curr.is_empty() {
+                    self.finished_gestures.push(self.gesture.split_off(0));
+                }
+            } else {
+                self.finished.insert(touch_id, (start, end));
+            }
         }
     }
 
@@ -201,6 +232,20 @@ impl TouchInput {
         })
     }
 
+    pub fn multi_finger_taps(&self, num_fingers: usize, max_distance: f64, max_period: f64) -> impl Iterator<Item = &Vec<(Touch, Touch)>> {
+        self.finished_gestures.iter().filter(move |touches| {
+            touches.len() == num_fingers && touches.iter().all(|(start, end)| {
+                Self::is_tap(start, end, max_distance, max_period)
+            })
+        })
+    }
+
+    pub fn long_presses(&self, max_distance: f64, min_period: f64) -> impl Iterator<Item = (&i32, &(Touch, Touch))> {
+        self.finished.iter().filter(move |(_, (start, end))| {
+            Self::is_long_press(start, end, max_distance, min_period)
+        })
+    }
+
     pub fn motions(&self) -> impl Iterator<Item = (&i32, (&Touch, &Touch, &Touch))> {
         self.active_curr.iter()
             .filter_map(move |(touch_id, (start, end_curr))| {
@@ -4,8 +4,17 @@
 mod input;
 mod gfx;
 mod piece;
+mod randomizer;
 mod board;
+mod fumen;
+mod rotation;
+mod scoring;
+mod mode;
+mod speed;
+mod replay;
+mod encoding;
 mod util;
 mod wasm_glue;
 mod js_api;
+mod platform;
 mod game;
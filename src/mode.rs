@@ -0,0 +1,435 @@
+use crate::{board, randomizer, scoring, speed, encoding, util};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Endless,
+    Sprint,
+    Ultra,
+    Marathon,
+    Zen,
+    Dig,
+    Survival,
+    Master,
+    Invisible,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Goal {
+    None,
+    Lines(u32),
+
+    // Time limit in milliseconds
+    Time(f64),
+
+    // Number of garbage rows the board starts with, which all need clearing
+    Garbage(u32),
+
+    Level(u32),
+}
+
+#[derive(Clone, Debug)]
+pub struct Mode {
+    pub kind: Kind,
+    pub goal: Goal,
+
+    // Level to start on, or the scoring system's first level if unset
+    pub start_level: Option<u32>,
+    pub has_level_select: bool,
+    pub has_level_progression: bool,
+
+    // Whether a blocked spawn ends the game, or clears the top of the stack
+    pub has_top_out: bool,
+    pub has_rising_garbage: bool,
+
+    // Levels 0-999 that advance with every piece and line, stopping at the
+    // end of each section of 100 levels until a line is cleared.
+    pub has_section_levels: bool,
+    pub speed: speed::Kind,
+    pub randomizer: randomizer::Kind,
+
+    // Locked blocks disappear and are only revealed when the game ends
+    pub fade: Option<board::Fade>,
+}
+
+const KINDS: [Kind; 9] = [
+    Kind::Endless,
+    Kind::Sprint,
+    Kind::Ultra,
+    Kind::Marathon,
+    Kind::Zen,
+    Kind::Dig,
+    Kind::Survival,
+    Kind::Master,
+    Kind::Invisible,
+];
+
+const SPRINT_LINES: u32 = 40;
+const ULTRA_TIME_LIMITS: [f64; 2] = [120_000.0, 180_000.0];
+const MARATHON_LINES: u32 = 150;
+const DIG_GARBAGE_ROWS: [u32; 2] = [10, 16];
+
+// Rows at the top that are kept free of garbage for the first piece to spawn in
+const DIG_FREE_ROWS: usize = 4;
+
+// Garbage rises every 8 seconds at first, 5% faster with each row, down to
+// one row per second.
+const SURVIVAL_INITIAL_INTERVAL: f64 = 8000.0;
+const SURVIVAL_MIN_INTERVAL: f64 = 1000.0;
+const SURVIVAL_INTERVAL_FACTOR: f64 = 0.95;
+
+const MASTER_LEVELS: u32 = 999;
+
+const INVISIBLE_FADES: [board::Fade; 2] = [
+    board::Fade { delay: 3000.0, duration: 1000.0 },
+    board::Fade { delay: 0.0, duration: 250.0 },
+];
+
+impl Kind {
+    pub fn from_id(id: u32) -> Option<Self> {
+        KINDS.get(id as usize).cloned()
+    }
+
+    pub fn id(&self) -> u32 {
+        KINDS.iter().position(|x| { x == self }).unwrap() as u32
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Endless => "ENDLESS",
+            Kind::Sprint => "SPRINT",
+            Kind::Ultra => "ULTRA",
+            Kind::Marathon => "MARATHON",
+            Kind::Zen => "ZEN",
+            Kind::Dig => "DIG",
+            Kind::Survival => "SURVIVAL",
+            Kind::Master => "MASTER",
+            Kind::Invisible => "INVISIBLE",
+        }
+    }
+
+    pub fn cycle(&self, offset: i32) -> Self {
+        let index = KINDS.iter().position(|x| { x == self }).unwrap() as i32;
+        let len = KINDS.len() as i32;
+        KINDS[(index + offset).rem_euclid(len) as usize]
+    }
+}
+
+impl Mode {
+    pub fn new(kind: Kind) -> Self {
+        let endless = Self {
+            kind,
+            goal: Goal::None,
+            start_level: None,
+            has_level_select: false,
+            has_level_progression: true,
+            has_top_out: true,
+            has_rising_garbage: false,
+            has_section_levels: false,
+            speed: speed::Kind::Standard,
+            randomizer: randomizer::Kind::SevenBag,
+            fade: None,
+        };
+
+        match kind {
+            Kind::Endless => endless,
+            Kind::Sprint => Self {
+                goal: Goal::Lines(SPRINT_LINES),
+                has_level_progression: false,
+                ..endless
+            },
+            Kind::Ultra => Self {
+                goal: Goal::Time(ULTRA_TIME_LIMITS[0]),
+                has_level_progression: false,
+                ..endless
+            },
+            Kind::Marathon => Self {
+                goal: Goal::Lines(MARATHON_LINES),
+                has_level_select: true,
+                ..endless
+            },
+            Kind::Zen => Self {
+                has_level_select: true,
+                has_level_progression: false,
+                has_top_out: false,
+                ..endless
+            },
+            Kind::Dig => Self {
+                goal: Goal::Garbage(DIG_GARBAGE_ROWS[0]),
+                has_level_progression: false,
+                ..endless
+            },
+            Kind::Survival => Self {
+                has_level_progression: false,
+                has_rising_garbage: true,
+                ..endless
+            },
+            Kind::Master => Self {
+                goal: Goal::Level(MASTER_LEVELS),
+                has_level_progression: false,
+                has_section_levels: true,
+                speed: speed::Kind::Master,
+                randomizer: randomizer::Kind::Tgm,
+                ..endless
+            },
+            Kind::Invisible => Self {
+                fade: Some(INVISIBLE_FADES[0]),
+                ..endless
+            },
+        }
+    }
+
+    // Only the kind and what can be changed by `cycle_option`,
+    // `set_goal_value` or `Game::set_randomizer` is stored, the rest follows
+    // from the kind.
+    pub fn encode(&self, writer: &mut encoding::Writer) {
+        writer.varint(self.kind.id() as u64);
+
+        match self.goal {
+            Goal::None => writer.varint(0),
+            Goal::Lines(n) => { writer.varint(1); writer.varint(n as u64) },
+            Goal::Time(limit) => { writer.varint(2); writer.f64(limit) },
+            Goal::Garbage(n) => { writer.varint(3); writer.varint(n as u64) },
+            Goal::Level(n) => { writer.varint(4); writer.varint(n as u64) },
+        }
+
+        writer.optional_varint(self.start_level.map(|x| { x as u64 }));
+        writer.optional_f64(self.fade.map(|x| { x.delay }));
+        writer.optional_f64(self.fade.map(|x| { x.duration }));
+        writer.varint(self.randomizer.id() as u64);
+    }
+
+    pub fn decode(reader: &mut encoding::Reader) -> Option<Self> {
+        let mut obj = Self::new(Kind::from_id(reader.varint()? as u32)?);
+
+        let count = |reader: &mut encoding::Reader| { reader.varint().map(|x| { x as u32 }).filter(|&x| { x >= 1 }) };
+        let is_duration = |x: &f64| { x.is_finite() && *x >= 0.0 };
+
+        obj.goal = match reader.varint()? {
+            0 => Goal::None,
+            1 => Goal::Lines(count(reader)?),
+            2 => Goal::Time(reader.f64().filter(|x| { is_duration(x) && *x > 0.0 })?),
+            3 => Goal::Garbage(count(reader)?),
+            4 => Goal::Level(count(reader)?),
+            _ => return None,
+        };
+
+        obj.start_level = reader.optional_varint()?.map(|x| { x as u32 });
+        obj.fade = match (reader.optional_f64()?, reader.optional_f64()?) {
+            (Some(delay), Some(duration)) if is_duration(&delay) && is_duration(&duration) => {
+                Some(board::Fade { delay, duration })
+            },
+            (Some(_), _) | (_, Some(_)) => return None,
+            _ => None,
+        };
+
+        obj.randomizer = randomizer::Kind::from_id(reader.varint()? as u32)?;
+        Some(obj)
+    }
+
+    // Time in milliseconds until the next garbage row rises
+    pub fn garbage_interval(&self, num_risen_rows: u32) -> f64 {
+        let exponent = num_risen_rows.min(i32::MAX as u32) as i32;
+        let interval = SURVIVAL_INITIAL_INTERVAL * SURVIVAL_INTERVAL_FACTOR.powi(exponent);
+        interval.max(SURVIVAL_MIN_INTERVAL)
+    }
+
+    pub fn start_level(&self, scoring_system: &dyn scoring::ScoringSystem) -> u32 {
+        if self.has_section_levels {
+            return 0;
+        }
+
+        let min_level = scoring_system.min_level();
+        let max_level = scoring_system.max_level();
+        util::clamp(self.start_level.unwrap_or(min_level), min_level, max_level)
+    }
+
+    // Steps through the presets of the mode's main option, if it has one
+    pub fn cycle_option(&mut self, offset: i32, scoring_system: &dyn scoring::ScoringSystem) {
+        if self.has_level_select {
+            let min_level = scoring_system.min_level() as i32;
+            let num_levels = (scoring_system.max_level() + 1) as i32 - min_level;
+            let level = self.start_level(scoring_system) as i32;
+            self.start_level = Some(((level - min_level + offset).rem_euclid(num_levels) + min_level) as u32);
+        } else if let Some(fade) = self.fade {
+            self.fade = Some(cycle_preset(&INVISIBLE_FADES, fade, offset));
+        } else {
+            match self.goal {
+                Goal::Time(limit) => self.goal = Goal::Time(cycle_preset(&ULTRA_TIME_LIMITS, limit, offset)),
+                Goal::Garbage(n) => self.goal = Goal::Garbage(cycle_preset(&DIG_GARBAGE_ROWS, n, offset)),
+                _ => {},
+            }
+        }
+    }
+
+    // Sets the number of lines, seconds, garbage rows or levels, depending on the kind of goal
+    pub fn set_goal_value(&mut self, value: u32) {
+        match self.goal {
+            Goal::None => {},
+            Goal::Lines(_) => self.goal = Goal::Lines(value.max(1)),
+            Goal::Time(_) => self.goal = Goal::Time(value.max(1) as f64 * 1000.0),
+            Goal::Garbage(_) => self.goal = Goal::Garbage(value.max(1)),
+            Goal::Level(_) => self.goal = Goal::Level(value.max(1)),
+        }
+    }
+
+    // Limits the number of garbage rows to what fits on the board
+    pub fn fit_to_board(&mut self, board_height: usize) {
+        if let Goal::Garbage(n) = self.goal {
+            self.goal = Goal::Garbage(n.min(max_garbage_rows(board_height)));
+        }
+    }
+
+    pub fn description(&self) -> String {
+        if let Some(fade) = self.fade {
+            if fade.delay > 0.0 {
+                return format!("BLOCKS FADE AFTER {} SECONDS", fade.delay / 1000.0);
+            } else {
+                return "BLOCKS VANISH ON LOCK".to_string();
+            }
+        }
+
+        match self.goal {
+            Goal::None if !self.has_top_out => "NO TOP OUT, CONSTANT SPEED".to_string(),
+            Goal::None if self.has_rising_garbage => "SURVIVE THE RISING GARBAGE".to_string(),
+            Goal::None => "PLAY UNTIL TOP OUT".to_string(),
+            Goal::Lines(n) => format!("CLEAR {} LINES", n),
+            Goal::Time(limit) => format!("SCORE IN {}", format_duration(limit)),
+            Goal::Garbage(n) => format!("DIG THROUGH {} GARBAGE ROWS", n),
+            Goal::Level(n) => format!("REACH LEVEL {} UP TO 20G", n),
+        }
+    }
+}
+
+fn cycle_preset<T: PartialEq + Copy>(presets: &[T], value: T, offset: i32) -> T {
+    let index = presets.iter().position(|&x| { x == value }).unwrap_or(0) as i32;
+    let len = presets.len() as i32;
+    presets[(index + offset).rem_euclid(len) as usize]
+}
+
+fn format_duration(duration: f64) -> String {
+    let seconds = (duration / 1000.0).round() as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+pub fn max_garbage_rows(board_height: usize) -> u32 {
+    board_height.saturating_sub(DIG_FREE_ROWS) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sprint() {
+        let mut mode = Mode::new(Kind::Sprint);
+        assert_eq!(mode.goal, Goal::Lines(40));
+        assert!(!mode.has_level_progression);
+        assert_eq!(mode.description(), "CLEAR 40 LINES");
+
+        mode.set_goal_value(20);
+        assert_eq!(mode.goal, Goal::Lines(20));
+        mode.set_goal_value(0);
+        assert_eq!(mode.goal, Goal::Lines(1));
+    }
+
+    #[test]
+    fn ultra() {
+        let scoring_system = scoring::Kind::Guideline.create();
+        let mut mode = Mode::new(Kind::Ultra);
+        assert_eq!(mode.goal, Goal::Time(120_000.0));
+        assert_eq!(mode.description(), "SCORE IN 2:00");
+
+        // The time limits wrap around either way
+        mode.cycle_option(1, scoring_system.as_ref());
+        assert_eq!(mode.goal, Goal::Time(180_000.0));
+        mode.cycle_option(1, scoring_system.as_ref());
+        assert_eq!(mode.goal, Goal::Time(120_000.0));
+        mode.cycle_option(-1, scoring_system.as_ref());
+        assert_eq!(mode.description(), "SCORE IN 3:00");
+
+        mode.set_goal_value(90);
+        assert_eq!(mode.goal, Goal::Time(90_000.0));
+        assert_eq!(mode.description(), "SCORE IN 1:30");
+    }
+
+    #[test]
+    fn marathon() {
+        let guideline = scoring::Kind::Guideline.create();
+        let nes = scoring::Kind::Nes.create();
+        let mut mode = Mode::new(Kind::Marathon);
+        assert_eq!(mode.goal, Goal::Lines(150));
+        assert!(mode.has_level_select && mode.has_level_progression);
+
+        // Levels start at the scoring system's first one and wrap around
+        assert_eq!(mode.start_level(guideline.as_ref()), 1);
+        assert_eq!(mode.start_level(nes.as_ref()), 0);
+        mode.cycle_option(-1, guideline.as_ref());
+        assert_eq!(mode.start_level, Some(20));
+        mode.cycle_option(1, guideline.as_ref());
+        assert_eq!(mode.start_level, Some(1));
+        mode.cycle_option(2, guideline.as_ref());
+        assert_eq!(mode.start_level, Some(3));
+
+        // Levels out of range for a scoring system are clamped
+        mode.start_level = Some(25);
+        assert_eq!(mode.start_level(guideline.as_ref()), 20);
+        assert_eq!(mode.start_level(nes.as_ref()), 25);
+        mode.start_level = Some(0);
+        assert_eq!(mode.start_level(guideline.as_ref()), 1);
+    }
+
+    #[test]
+    fn garbage_interval() {
+        let mode = Mode::new(Kind::Survival);
+        assert_eq!(mode.garbage_interval(0), 8000.0);
+        assert_eq!(mode.garbage_interval(1), 7600.0);
+
+        // 8000 * 0.95^40 is just above one second, 8000 * 0.95^41 below it
+        assert!(mode.garbage_interval(40) > 1000.0);
+        assert_eq!(mode.garbage_interval(41), 1000.0);
+        assert_eq!(mode.garbage_interval(u32::MAX), 1000.0);
+    }
+
+    #[test]
+    fn master() {
+        let scoring_system = scoring::Kind::Guideline.create();
+        let mut mode = Mode::new(Kind::Master);
+        assert_eq!(mode.goal, Goal::Level(999));
+        assert_eq!(mode.description(), "REACH LEVEL 999 UP TO 20G");
+
+        // Section levels always start at 0
+        mode.start_level = Some(5);
+        assert_eq!(mode.start_level(scoring_system.as_ref()), 0);
+
+        mode.set_goal_value(500);
+        assert_eq!(mode.goal, Goal::Level(500));
+    }
+
+    #[test]
+    fn invisible() {
+        let scoring_system = scoring::Kind::Guideline.create();
+        let mut mode = Mode::new(Kind::Invisible);
+        assert_eq!(mode.description(), "BLOCKS FADE AFTER 3 SECONDS");
+
+        mode.cycle_option(1, scoring_system.as_ref());
+        assert_eq!(mode.fade, Some(board::Fade { delay: 0.0, duration: 250.0 }));
+        assert_eq!(mode.description(), "BLOCKS VANISH ON LOCK");
+        mode.cycle_option(1, scoring_system.as_ref());
+        assert_eq!(mode.fade, Some(INVISIBLE_FADES[0]));
+
+        // The fade survives encoding, but not a negative duration
+        let round_trip = |mode: &Mode| {
+            let mut writer = encoding::Writer::new();
+            mode.encode(&mut writer);
+            let bytes = writer.into_bytes();
+            Mode::decode(&mut encoding::Reader::new(&bytes))
+        };
+
+        mode.cycle_option(-1, scoring_system.as_ref());
+        assert_eq!(round_trip(&mode).unwrap().fade, mode.fade);
+
+        mode.fade = Some(board::Fade { delay: 0.0, duration: -1.0 });
+        assert!(round_trip(&mode).is_none());
+    }
+}
+
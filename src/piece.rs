@@ -1,8 +1,12 @@
 use std::{cmp, fmt};
 use std::collections::vec_deque::VecDeque;
 
-use crate::{gfx, util, js_api};
+use crate::{gfx, randomizer, encoding, util};
+use crate::platform::{Platform};
 
+pub const MAX_PEEK: usize = 6;
+
+#[derive(PartialEq)]
 struct BlockMatrix {
     stride: usize,
     data: Vec<bool>,
@@ -17,12 +21,9 @@ pub struct Piece {
 pub struct Bag {
     pieces: Vec<Piece>,
     indices: VecDeque<usize>,
-}
-
-fn shuffle<T>(min: usize, max: usize, deque: &mut VecDeque<T>) {
-    for (i, j) in util::random_index_pairs(min, max) {
-        deque.swap(i, j)
-    }
+    held: Option<usize>,
+    randomizer: Box<dyn randomizer::Randomizer>,
+    rng: util::Pcg32,
 }
 
 impl BlockMatrix {
@@ -105,6 +106,20 @@ impl Piece {
         }
     }
 
+    fn with_rotations<E, T, S>(name: S, color: gfx::Color, data: [T; 4]) -> Self
+        where E: AsRef<[u8]>,
+              T: AsRef<[E]>,
+              S: Into<String>,
+    {
+        let [a, b, c, d] = data;
+
+        Self {
+            name: name.into(),
+            color,
+            rotations: [BlockMatrix::new(a), BlockMatrix::new(b), BlockMatrix::new(c), BlockMatrix::new(d)],
+        }
+    }
+
     pub fn bounds(&self, rotation: usize) -> ((usize, usize), (usize, usize)) {
         let mut x1 = usize::max_value();
         let mut x2 = usize::min_value();
@@ -121,35 +136,44 @@ impl Piece {
         ((x1, y1), (x2, y2))
     }
 
+    // Same blocks in the same matrices, regardless of name and color
+    pub fn has_same_shape(&self, other: &Piece) -> bool {
+        self.rotations == other.rotations
+    }
+
     pub fn iter_coords(&self, rotation: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
         let v = &self.rotations[rotation % self.rotations.len()];
         v.iter_coords()
     }
 
-    pub fn draw(&self, position: &util::Position, rotation: usize, intensity: f64) {
+    pub fn draw<P: Platform>(&self, platform: &mut P, position: &util::Position, rotation: usize, intensity: f64) {
         let color = self.color.fade(intensity);
 
         for (bx, by) in self.iter_coords(rotation) {
             let dx = position.x + (bx as i32);
             let dy = position.y + (by as i32);
-            js_api::draw_block(dx as u32, dy as u32, color.to_argb32());
+
+            // Skip the parts in the hidden rows above the board
+            if dy >= 0 {
+                platform.draw_block(dx as u32, dy as u32, color.to_argb32());
+            }
         }
     }
 }
 
 impl Bag {
-    pub fn new(pieces: Vec<Piece>) -> Self {
-        let mut obj = Self { pieces, indices: VecDeque::new() };
+    pub fn new(pieces: Vec<Piece>, randomizer: randomizer::Kind, seed: u32) -> Self {
+        let randomizer = randomizer.create(&pieces);
+        let mut obj = Self { pieces, indices: VecDeque::new(), held: None, randomizer, rng: util::Pcg32::new(seed) };
         obj.fill();
 
         obj
     }
 
     fn fill(&mut self) {
-        if self.indices.len() < self.pieces.len() {
-            let old_len = self.indices.len();
-            self.indices.extend(0..self.pieces.len());
-            shuffle(old_len, self.indices.len(), &mut self.indices);
+        while self.indices.len() < MAX_PEEK + 1 {
+            let index = self.randomizer.next(&mut self.rng);
+            self.indices.push_back(index);
         }
     }
 
@@ -163,10 +187,155 @@ impl Bag {
             .unwrap()
     }
 
+    pub fn peek(&self, n: usize) -> impl Iterator<Item = &Piece> {
+        assert!(n <= MAX_PEEK);
+        self.indices.iter()
+            .skip(1)
+            .take(n)
+            .map(move |&index| { &self.pieces[index] })
+    }
+
+    pub fn held(&self) -> Option<&Piece> {
+        self.held.map(|index| { &self.pieces[index] })
+    }
+
+    // The pieces themselves aren't stored, only which ones come next
+    pub fn encode(&self, writer: &mut encoding::Writer) {
+        writer.varint(self.indices.len() as u64);
+        for &index in self.indices.iter() {
+            writer.varint(index as u64);
+        }
+
+        writer.optional_varint(self.held.map(|x| { x as u64 }));
+        self.randomizer.encode(writer);
+        self.rng.encode(writer);
+    }
+
+    pub fn decode(pieces: Vec<Piece>, randomizer: randomizer::Kind, reader: &mut encoding::Reader) -> Option<Self> {
+        let len = reader.varint()? as usize;
+        if len > reader.remaining() {
+            return None;
+        }
+
+        let indices = (0..len)
+            .map(|_| { reader.varint().map(|x| { x as usize }).filter(|&x| { x < pieces.len() }) })
+            .collect::<Option<VecDeque<usize>>>()?;
+
+        let held = match reader.optional_varint()? {
+            Some(x) if x as usize >= pieces.len() => return None,
+            held => held.map(|x| { x as usize }),
+        };
+
+        let mut randomizer = randomizer.create(&pieces);
+        randomizer.restore(reader)?;
+
+        let rng = util::Pcg32::decode(reader)?;
+        if indices.is_empty() {
+            return None;
+        }
+
+        let mut obj = Self { pieces, indices, held, randomizer, rng };
+        obj.fill();
+
+        Some(obj)
+    }
+
+    // Makes the piece at `index` current, ahead of the queue
+    pub fn push_current(&mut self, index: usize) {
+        self.indices.push_front(index);
+    }
+
     pub fn advance(&mut self) {
         self.indices.pop_front();
         self.fill();
     }
+
+    pub fn hold(&mut self) {
+        let current = self.indices.pop_front().unwrap();
+        match self.held.replace(current) {
+            Some(index) => self.indices.push_front(index),
+            None => self.fill(),
+        }
+    }
+}
+
+// Parses a piece set from text. Pieces are separated by blank lines, each
+// starting with its name and color, followed by the rows of a square matrix
+// of free (`.`) and solid (`#`) blocks that rotates about its center, so the
+// blocks should be centered in it:
+//
+//     T #aa00ff
+//     .#.
+//     ###
+//     ...
+//
+// Lines starting with `//` are ignored.
+pub fn parse_piece_set(text: &str) -> Result<Vec<Piece>, String> {
+    let mut lines = text.lines()
+        .enumerate()
+        .map(|(index, line)| { (index + 1, line.trim()) })
+        .filter(|(_, line)| { !line.starts_with("//") })
+        .peekable();
+
+    let mut pieces: Vec<Piece> = Vec::new();
+    loop {
+        while let Some((_, "")) = lines.peek() {
+            lines.next();
+        }
+
+        let (line_number, header) = match lines.next() {
+            Some(line) => line,
+            None => break,
+        };
+
+        let (name, color) = match header.split_whitespace().collect::<Vec<_>>()[..] {
+            [name, color] => (name, color),
+            _ => return Err(format!("Line {}: expected a piece name and color", line_number)),
+        };
+
+        if pieces.iter().any(|x| { x.name == name }) {
+            return Err(format!("Line {}: duplicate piece name \"{}\"", line_number, name));
+        }
+
+        let color = color.strip_prefix('#')
+            .filter(|x| { x.len() == 6 })
+            .and_then(|x| { u32::from_str_radix(x, 16).ok() })
+            .ok_or_else(|| { format!("Line {}: expected a color like #aa00ff", line_number) })?;
+
+        let mut rows: Vec<Vec<u8>> = Vec::new();
+        while let Some(&(line_number, line)) = lines.peek() {
+            if line.is_empty() {
+                break;
+            }
+
+            let row = line.chars()
+                .map(|c| match c {
+                    '.' => Ok(0),
+                    '#' => Ok(1),
+                    _ => Err(format!("Line {}: unexpected character '{}'", line_number, c)),
+                })
+                .collect::<Result<Vec<u8>, String>>()?;
+
+            rows.push(row);
+            lines.next();
+        }
+
+        if rows.iter().any(|x| { x.len() != rows.len() }) {
+            return Err(format!("Line {}: the rows of piece \"{}\" don't form a square", line_number, name));
+        }
+
+        if !rows.iter().flatten().any(|&x| { x != 0 }) {
+            return Err(format!("Line {}: piece \"{}\" has no blocks", line_number, name));
+        }
+
+        pieces.push(Piece::new(name, gfx::Color::from_argb32(color), rows));
+    }
+
+    if pieces.is_empty() {
+        return Err("No pieces defined".to_string());
+    }
+
+    Ok(pieces)
 }
 
 pub fn make_standard() -> Vec<Piece> {
@@ -251,3 +420,182 @@ pub fn make_ttc_original() -> Vec<Piece> {
         ]),
     ]
 }
+
+pub fn make_ars() -> Vec<Piece> {
+    // See https://tetris.wiki/Arika_Rotation_System
+    vec![
+        Piece::with_rotations("I", gfx::Color::from_argb32(0x00ffff), [
+            [[0, 0, 0, 0], [1, 1, 1, 1], [0, 0, 0, 0], [0, 0, 0, 0]],
+            [[0, 0, 1, 0], [0, 0, 1, 0], [0, 0, 1, 0], [0, 0, 1, 0]],
+            [[0, 0, 0, 0], [1, 1, 1, 1], [0, 0, 0, 0], [0, 0, 0, 0]],
+            [[0, 0, 1, 0], [0, 0, 1, 0], [0, 0, 1, 0], [0, 0, 1, 0]],
+        ]),
+        Piece::with_rotations("O", gfx::Color::from_argb32(0xffff00), [
+            [[0, 0, 0], [0, 1, 1], [0, 1, 1]],
+            [[0, 0, 0], [0, 1, 1], [0, 1, 1]],
+            [[0, 0, 0], [0, 1, 1], [0, 1, 1]],
+            [[0, 0, 0], [0, 1, 1], [0, 1, 1]],
+        ]),
+        Piece::with_rotations("J", gfx::Color::from_argb32(0x0000ff), [
+            [[0, 0, 0], [1, 1, 1], [0, 0, 1]],
+            [[0, 1, 0], [0, 1, 0], [1, 1, 0]],
+            [[0, 0, 0], [1, 0, 0], [1, 1, 1]],
+            [[0, 1, 1], [0, 1, 0], [0, 1, 0]],
+        ]),
+        Piece::with_rotations("L", gfx::Color::from_argb32(0xffa500), [
+            [[0, 0, 0], [1, 1, 1], [1, 0, 0]],
+            [[1, 1, 0], [0, 1, 0], [0, 1, 0]],
+            [[0, 0, 0], [0, 0, 1], [1, 1, 1]],
+            [[0, 1, 0], [0, 1, 0], [0, 1, 1]],
+        ]),
+        Piece::with_rotations("S", gfx::Color::from_argb32(0x00ff00), [
+            [[0, 0, 0], [0, 1, 1], [1, 1, 0]],
+            [[1, 0, 0], [1, 1, 0], [0, 1, 0]],
+            [[0, 0, 0], [0, 1, 1], [1, 1, 0]],
+            [[1, 0, 0], [1, 1, 0], [0, 1, 0]],
+        ]),
+        Piece::with_rotations("Z", gfx::Color::from_argb32(0xff0000), [
+            [[0, 0, 0], [1, 1, 0], [0, 1, 1]],
+            [[0, 0, 1], [0, 1, 1], [0, 1, 0]],
+            [[0, 0, 0], [1, 1, 0], [0, 1, 1]],
+            [[0, 0, 1], [0, 1, 1], [0, 1, 0]],
+        ]),
+        Piece::with_rotations("T", gfx::Color::from_argb32(0xaa00ff), [
+            [[0, 0, 0], [1, 1, 1], [0, 1, 0]],
+            [[0, 1, 0], [1, 1, 0], [0, 1, 0]],
+            [[0, 0, 0], [0, 1, 0], [1, 1, 1]],
+            [[0, 1, 0], [0, 1, 1], [0, 1, 0]],
+        ]),
+    ]
+}
+
+pub fn make_nes() -> Vec<Piece> {
+    // See https://tetris.wiki/Nintendo_Rotation_System
+    vec![
+        Piece::with_rotations("I", gfx::Color::from_argb32(0x00ffff), [
+            [[0, 0, 0, 0], [0, 0, 0, 0], [1, 1, 1, 1], [0, 0, 0, 0]],
+            [[0, 0, 1, 0], [0, 0, 1, 0], [0, 0, 1, 0], [0, 0, 1, 0]],
+            [[0, 0, 0, 0], [0, 0, 0, 0], [1, 1, 1, 1], [0, 0, 0, 0]],
+            [[0, 0, 1, 0], [0, 0, 1, 0], [0, 0, 1, 0], [0, 0, 1, 0]],
+        ]),
+        Piece::new("O", gfx::Color::from_argb32(0xffff00), [
+                   [1, 1],
+                   [1, 1],
+        ]),
+        Piece::with_rotations("J", gfx::Color::from_argb32(0x0000ff), [
+            [[0, 0, 0], [1, 1, 1], [0, 0, 1]],
+            [[0, 1, 0], [0, 1, 0], [1, 1, 0]],
+            [[1, 0, 0], [1, 1, 1], [0, 0, 0]],
+            [[0, 1, 1], [0, 1, 0], [0, 1, 0]],
+        ]),
+        Piece::with_rotations("L", gfx::Color::from_argb32(0xffa500), [
+            [[0, 0, 0], [1, 1, 1], [1, 0, 0]],
+            [[1, 1, 0], [0, 1, 0], [0, 1, 0]],
+            [[0, 0, 1], [1, 1, 1], [0, 0, 0]],
+            [[0, 1, 0], [0, 1, 0], [0, 1, 1]],
+        ]),
+        Piece::with_rotations("S", gfx::Color::from_argb32(0x00ff00), [
+            [[0, 0, 0], [0, 1, 1], [1, 1, 0]],
+            [[0, 1, 0], [0, 1, 1], [0, 0, 1]],
+            [[0, 0, 0], [0, 1, 1], [1, 1, 0]],
+            [[0, 1, 0], [0, 1, 1], [0, 0, 1]],
+        ]),
+        Piece::with_rotations("Z", gfx::Color::from_argb32(0xff0000), [
+            [[0, 0, 0], [1, 1, 0], [0, 1, 1]],
+            [[0, 0, 1], [0, 1, 1], [0, 1, 0]],
+            [[0, 0, 0], [1, 1, 0], [0, 1, 1]],
+            [[0, 0, 1], [0, 1, 1], [0, 1, 0]],
+        ]),
+        Piece::with_rotations("T", gfx::Color::from_argb32(0xaa00ff), [
+            [[0, 0, 0], [1, 1, 1], [0, 1, 0]],
+            [[0, 1, 0], [1, 1, 0], [0, 1, 0]],
+            [[0, 1, 0], [1, 1, 1], [0, 0, 0]],
+            [[0, 1, 0], [0, 1, 1], [0, 1, 0]],
+        ]),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deal(bag: &mut Bag, num_pieces: usize) -> Vec<String> {
+        (0..num_pieces)
+            .map(|_| {
+                let name = bag.current().name.clone();
+                bag.advance();
+                name
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bag_is_seeded() {
+        let new_bag = |seed| { Bag::new(make_standard(), randomizer::Kind::SevenBag, seed) };
+
+        assert_eq!(deal(&mut new_bag(1), 50), deal(&mut new_bag(1), 50));
+        assert_ne!(deal(&mut new_bag(1), 50), deal(&mut new_bag(2), 50));
+    }
+
+    #[test]
+    fn peek() {
+        let mut bag = Bag::new(make_standard(), randomizer::Kind::SevenBag, 3);
+        let next: Vec<String> = bag.peek(MAX_PEEK).map(|x| { x.name.clone() }).collect();
+        assert_eq!(next.len(), MAX_PEEK);
+
+        bag.advance();
+        assert_eq!(bag.current().name, next[0]);
+        assert_eq!(deal(&mut bag, MAX_PEEK), next);
+    }
+
+    #[test]
+    fn hold() {
+        let mut bag = Bag::new(make_standard(), randomizer::Kind::SevenBag, 3);
+        let first = bag.current().name.clone();
+        let second = bag.peek(1).next().unwrap().name.clone();
+        assert!(bag.held().is_none());
+
+        // The first hold brings in the next piece, later ones swap
+        bag.hold();
+        assert_eq!(bag.held().unwrap().name, first);
+        assert_eq!(bag.current().name, second);
+
+        bag.hold();
+        assert_eq!(bag.held().unwrap().name, second);
+        assert_eq!(bag.current().name, first);
+    }
+
+    #[test]
+    fn parse_shipped_piece_sets() {
+        for text in &[include_str!("../web/pieces/mixed.txt"), include_str!("../web/pieces/pentomino.txt")] {
+            assert!(parse_piece_set(text).is_ok());
+        }
+
+        let pieces = parse_piece_set("// Comment\n\n  O #ffff00  \n##\n##\n\n\n\nI #00ffff\n....\n####\n....\n....").unwrap();
+        assert_eq!(pieces.iter().map(|x| { x.name.as_str() }).collect::<Vec<_>>(), vec!["O", "I"]);
+        assert!(pieces[0].has_same_shape(&Piece::new("O", gfx::Color::from_argb32(0xffff00), vec![vec![1, 1], vec![1, 1]])));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("", "No pieces defined"),
+            ("// Only a comment\n\n", "No pieces defined"),
+            ("O\n##\n##", "Line 1: expected a piece name and color"),
+            ("O #ffff00 extra\n##\n##", "Line 1: expected a piece name and color"),
+            ("O #ffff00\n##\n##\n\nO #ff0000\n##\n##", "Line 5: duplicate piece name \"O\""),
+            ("O ffff00\n##\n##", "Line 1: expected a color like #aa00ff"),
+            ("O #fff\n##\n##", "Line 1: expected a color like #aa00ff"),
+            ("O #gggggg\n##\n##", "Line 1: expected a color like #aa00ff"),
+            ("O #ffff00\n##\n#x", "Line 3: unexpected character 'x'"),
+            ("I #00ffff\n####", "Line 1: the rows of piece \"I\" don't form a square"),
+            ("O #ffff00\n##\n#", "Line 1: the rows of piece \"O\" don't form a square"),
+            ("O #ffff00\n..\n..", "Line 1: piece \"O\" has no blocks"),
+            ("O #ffff00", "Line 1: piece \"O\" has no blocks"),
+        ];
+
+        for &(text, error) in cases.iter() {
+            assert_eq!(parse_piece_set(text).err().as_deref(), Some(error), "{:?}", text);
+        }
+    }
+}
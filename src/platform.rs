@@ -0,0 +1,87 @@
+use std::collections::{HashMap};
+
+use crate::{util, js_api};
+
+pub trait Platform: 'static {
+    fn draw_block(&mut self, x: u32, y: u32, color: u32);
+    fn html(&mut self, id: &str, html: &str);
+    fn random(&mut self) -> f64;
+    fn log(&mut self, message: &str);
+}
+
+pub struct JsPlatform;
+
+pub struct RecordingPlatform {
+    blocks: HashMap<(u32, u32), u32>,
+    html: HashMap<String, String>,
+    messages: Vec<String>,
+    num_draw_calls: usize,
+
+    rng: util::Pcg32,
+}
+
+impl Platform for JsPlatform {
+    fn draw_block(&mut self, x: u32, y: u32, color: u32) {
+        js_api::draw_block(x, y, color);
+    }
+
+    fn html(&mut self, id: &str, html: &str) {
+        js_api::html(id, html);
+    }
+
+    fn random(&mut self) -> f64 {
+        js_api::random()
+    }
+
+    fn log(&mut self, message: &str) {
+        js_api::console_log(message);
+    }
+}
+
+impl RecordingPlatform {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            blocks: HashMap::new(),
+            html: HashMap::new(),
+            messages: Vec::new(),
+            num_draw_calls: 0,
+
+            rng: util::Pcg32::new(seed),
+        }
+    }
+
+    pub fn get_block(&self, x: u32, y: u32) -> u32 {
+        self.blocks.get(&(x, y)).cloned().unwrap_or(0)
+    }
+
+    pub fn get_html(&self, id: &str) -> Option<&str> {
+        self.html.get(id).map(|x| { x.as_str() })
+    }
+
+    pub fn messages(&self) -> &[String] {
+        &self.messages
+    }
+
+    pub fn num_draw_calls(&self) -> usize {
+        self.num_draw_calls
+    }
+}
+
+impl Platform for RecordingPlatform {
+    fn draw_block(&mut self, x: u32, y: u32, color: u32) {
+        self.blocks.insert((x, y), color);
+        self.num_draw_calls += 1;
+    }
+
+    fn html(&mut self, id: &str, html: &str) {
+        self.html.insert(id.to_string(), html.to_string());
+    }
+
+    fn random(&mut self) -> f64 {
+        self.rng.next_f64()
+    }
+
+    fn log(&mut self, message: &str) {
+        self.messages.push(message.to_string());
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::vec_deque::VecDeque;
+
+use crate::{piece, encoding, util};
+
+// Decides which piece comes next, as an index into the piece set
+pub trait Randomizer {
+    fn next(&mut self, rng: &mut util::Pcg32) -> usize;
+
+    fn encode(&self, writer: &mut encoding::Writer);
+    fn restore(&mut self, reader: &mut encoding::Reader) -> Option<()>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    SevenBag,
+    FourteenBag,
+    Memoryless,
+    Nes,
+    Tgm,
+}
+
+// Deals the whole piece set `num_copies` times in random order before
+// starting over, see https://tetris.wiki/Random_Generator
+pub struct ShuffledBag {
+    num_pieces: usize,
+    num_copies: usize,
+    queue: VecDeque<usize>,
+}
+
+pub struct Memoryless {
+    num_pieces: usize,
+}
+
+// Rolls an extra "reroll" value and rerolls once if it or a repeat of the
+// previous piece comes up, see https://tetris.wiki/Tetris_(NES,_Nintendo)
+pub struct Nes {
+    num_pieces: usize,
+    previous: Option<usize>,
+}
+
+// Rerolls up to `TGM_NUM_ROLLS` times while the piece is one of the last
+// four, see https://tetris.wiki/TGM_randomizer
+pub struct Tgm {
+    num_pieces: usize,
+    history: VecDeque<usize>,
+
+    // S, Z and O, which are never dealt first as they could force an overhang
+    unfair_first: Vec<usize>,
+    is_first: bool,
+}
+
+const TGM_HISTORY_LENGTH: usize = 4;
+const TGM_NUM_ROLLS: usize = 6;
+
+impl Kind {
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Kind::SevenBag),
+            1 => Some(Kind::FourteenBag),
+            2 => Some(Kind::Memoryless),
+            3 => Some(Kind::Nes),
+            4 => Some(Kind::Tgm),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            Kind::SevenBag => 0,
+            Kind::FourteenBag => 1,
+            Kind::Memoryless => 2,
+            Kind::Nes => 3,
+            Kind::Tgm => 4,
+        }
+    }
+
+    pub fn create(&self, pieces: &[piece::Piece]) -> Box<dyn Randomizer> {
+        let num_pieces = pieces.len();
+        let find = |names: &[&str]| -> Vec<usize> {
+            pieces.iter()
+                .enumerate()
+                .filter(|(_, piece)| { names.contains(&piece.name.as_str()) })
+                .map(|(index, _)| { index })
+                .collect()
+        };
+
+        match self {
+            Kind::SevenBag => Box::new(ShuffledBag { num_pieces, num_copies: 1, queue: VecDeque::new() }),
+            Kind::FourteenBag => Box::new(ShuffledBag { num_pieces, num_copies: 2, queue: VecDeque::new() }),
+            Kind::Memoryless => Box::new(Memoryless { num_pieces }),
+            Kind::Nes => Box::new(Nes { num_pieces, previous: None }),
+            Kind::Tgm => {
+                // TGM2 starts out with a history of Z, S, S, Z
+                let (s, z) = (find(&["S"]), find(&["Z"]));
+                let history = z.iter().chain(s.iter()).chain(s.iter()).chain(z.iter()).cloned().collect();
+
+                Box::new(Tgm { num_pieces, history, unfair_first: find(&["S", "Z", "O"]), is_first: true })
+            },
+        }
+    }
+}
+
+fn encode_indices<'a, I>(writer: &mut encoding::Writer, indices: I)
+    where I: ExactSizeIterator<Item = &'a usize>
+{
+    writer.varint(indices.len() as u64);
+    for &index in indices {
+        writer.varint(index as u64);
+    }
+}
+
+fn decode_indices(reader: &mut encoding::Reader, num_pieces: usize) -> Option<VecDeque<usize>> {
+    let len = reader.varint()? as usize;
+    if len > reader.remaining() {
+        return None;
+    }
+
+    (0..len)
+        .map(|_| { reader.varint().map(|x| { x as usize }).filter(|&x| { x < num_pieces }) })
+        .collect()
+}
+
+impl Randomizer for ShuffledBag {
+    fn next(&mut self, rng: &mut util::Pcg32) -> usize {
+        if self.queue.is_empty() {
+            let num_pieces = self.num_pieces;
+            self.queue.extend((0..self.num_copies).flat_map(|_| { 0..num_pieces }));
+
+            let len = self.queue.len();
+            for (i, j) in util::random_index_pairs(0, len, rng) {
+                self.queue.swap(i, j)
+            }
+        }
+
+        self.queue.pop_front().unwrap()
+    }
+
+    fn encode(&self, writer: &mut encoding::Writer) {
+        encode_indices(writer, self.queue.iter());
+    }
+
+    fn restore(&mut self, reader: &mut encoding::Reader) -> Option<()> {
+        self.queue = decode_indices(reader, self.num_pieces)?;
+        Some(())
+    }
+}
+
+impl Randomizer for Memoryless {
+    fn next(&mut self, rng: &mut util::Pcg32) -> usize {
+        rng.next_below(self.num_pieces as u32) as usize
+    }
+
+    fn encode(&self, _writer: &mut encoding::Writer) {}
+
+    fn restore(&mut self, _reader: &mut encoding::Reader) -> Option<()> {
+        Some(())
+    }
+}
+
+impl Randomizer for Nes {
+    fn next(&mut self, rng: &mut util::Pcg32) -> usize {
+        let mut index = rng.next_below(self.num_pieces as u32 + 1) as usize;
+        if index == self.num_pieces || Some(index) == self.previous {
+            index = rng.next_below(self.num_pieces as u32) as usize;
+        }
+
+        self.previous = Some(index);
+        index
+    }
+
+    fn encode(&self, writer: &mut encoding::Writer) {
+        writer.optional_varint(self.previous.map(|x| { x as u64 }));
+    }
+
+    fn restore(&mut self, reader: &mut encoding::Reader) -> Option<()> {
+        self.previous = reader.optional_varint()?.map(|x| { x as usize }).filter(|&x| { x < self.num_pieces });
+        Some(())
+    }
+}
+
+impl Randomizer for Tgm {
+    fn next(&mut self, rng: &mut util::Pcg32) -> usize {
+        let fair_first: Vec<usize> = (0..self.num_pieces)
+            .filter(|x| { !self.unfair_first.contains(x) })
+            .collect();
+
+        let index = {
+            if self.is_first && !fair_first.is_empty() {
+                fair_first[rng.next_below(fair_first.len() as u32) as usize]
+            } else {
+                let mut index = 0;
+                for _ in 0..TGM_NUM_ROLLS {
+                    index = rng.next_below(self.num_pieces as u32) as usize;
+                    if !self.history.contains(&index) {
+                        break;
+                    }
+                }
+
+                index
+            }
+        };
+
+        self.is_first = false;
+        self.history.push_back(index);
+        while self.history.len() > TGM_HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+
+        index
+    }
+
+    fn encode(&self, writer: &mut encoding::Writer) {
+        encode_indices(writer, self.history.iter());
+        writer.u8(self.is_first as u8);
+    }
+
+    fn restore(&mut self, reader: &mut encoding::Reader) -> Option<()> {
+        self.history = decode_indices(reader, self.num_pieces)?;
+        self.is_first = reader.u8()? != 0;
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deal(kind: Kind, seed: u32, count: usize) -> (Vec<piece::Piece>, Vec<usize>) {
+        let pieces = piece::make_ttc_original();
+        let mut randomizer = kind.create(&pieces);
+        let mut rng = util::Pcg32::new(seed);
+        let indices = (0..count).map(|_| { randomizer.next(&mut rng) }).collect();
+
+        (pieces, indices)
+    }
+
+    fn counts(indices: &[usize], num_pieces: usize) -> Vec<usize> {
+        let mut counts = vec![0; num_pieces];
+        for &index in indices {
+            counts[index] += 1;
+        }
+
+        counts
+    }
+
+    #[test]
+    fn bags() {
+        for &(kind, num_copies) in &[(Kind::SevenBag, 1), (Kind::FourteenBag, 2)] {
+            let (pieces, indices) = deal(kind, 1, 7 * num_copies * 100);
+            for bag in indices.chunks(pieces.len() * num_copies) {
+                assert_eq!(counts(bag, pieces.len()), vec![num_copies; pieces.len()], "{:?}", kind);
+            }
+        }
+    }
+
+    #[test]
+    fn distribution() {
+        let num_pieces = 7000;
+        for &kind in &[Kind::Memoryless, Kind::Nes, Kind::Tgm] {
+            let (pieces, indices) = deal(kind, 2, num_pieces);
+            let expected = num_pieces / pieces.len();
+            for (index, &count) in counts(&indices, pieces.len()).iter().enumerate() {
+                assert!(count > expected * 8 / 10 && count < expected * 12 / 10, "{:?} dealt {} {} times", kind, pieces[index].name, count);
+            }
+        }
+    }
+
+    #[test]
+    fn repeats() {
+        let count_repeats = |indices: &[usize]| { indices.windows(2).filter(|x| { x[0] == x[1] }).count() };
+        let num_pieces = 7000;
+
+        // Memoryless repeats a seventh of the time, the NES randomizer about a
+        // 56th and TGM hardly ever
+        let (_, indices) = deal(Kind::Memoryless, 3, num_pieces);
+        assert!(count_repeats(&indices) > num_pieces / 10);
+
+        let (_, indices) = deal(Kind::Nes, 3, num_pieces);
+        assert!(count_repeats(&indices) > num_pieces / 112 && count_repeats(&indices) < num_pieces / 28);
+
+        let (_, indices) = deal(Kind::Tgm, 3, num_pieces);
+        assert!(count_repeats(&indices) < num_pieces / 100);
+    }
+
+    #[test]
+    fn tgm_first_piece() {
+        for seed in 0..200 {
+            let (pieces, indices) = deal(Kind::Tgm, seed, 1);
+            assert!(!["S", "Z", "O"].contains(&pieces[indices[0]].name.as_str()));
+        }
+    }
+
+    #[test]
+    fn restore() {
+        let pieces = piece::make_ttc_original();
+        for id in 0..5 {
+            let kind = Kind::from_id(id).unwrap();
+            let mut randomizer = kind.create(&pieces);
+            let mut rng = util::Pcg32::new(4);
+            for _ in 0..10 {
+                randomizer.next(&mut rng);
+            }
+
+            let mut writer = encoding::Writer::new();
+            randomizer.encode(&mut writer);
+            let bytes = writer.into_bytes();
+
+            let mut restored = kind.create(&pieces);
+            restored.restore(&mut encoding::Reader::new(&bytes)).unwrap();
+
+            let mut restored_rng = rng.clone();
+            for _ in 0..30 {
+                assert_eq!(randomizer.next(&mut rng), restored.next(&mut restored_rng), "{:?}", kind);
+            }
+        }
+    }
+}
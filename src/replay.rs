@@ -0,0 +1,352 @@
+use crate::{encoding};
+
+// Recorded input of a single game, from the frame it started on until it
+// ended. Fed back into `game::Game`, it reproduces the game exactly, since
+// everything random is derived from the seed in its settings.
+//
+// Binary format, with varints as LEB128 (signed ones zigzag encoded):
+//
+//     magic         "RSTR"
+//     version       u8, `VERSION`
+//     ruleset       varint, `RULESET`
+//     settings      varint length, settings the game started with (seed,
+//                   board size, mode, ...) as encoded by `game::Settings`
+//     position      varint length, UTF-8 fumen the game started from, or empty
+//     start time    f64, timestamp of the frame the game started on
+//     events        see below
+//     checksum      u32, little-endian FNV-1a of all preceding bytes
+//
+// Every event starts with a varint. If its lowest bit is clear, it is a
+// frame and the remaining bits are the signed difference in milliseconds to
+// the previous frame's timestamp (or the start time), so a frame at 60 Hz
+// takes a single byte. Otherwise the remaining bits are one of the `TAG_*` constants below,
+// followed by the event's arguments:
+//
+//     key press/release    varint key code
+//     touch events         signed touch id, signed x, signed y
+//     pause                -
+//     mode, goal, rotation,
+//     scoring, preview,
+//     hidden rows,
+//     randomizer           varint
+//     piece set            varint length, UTF-8 definition
+//     load position        varint length, UTF-8 fumen
+//     lock delay           f64 duration, signed max resets
+//     garbage              varint holes, f64 messiness
+
+const MAGIC: &[u8; 4] = b"RSTR";
+const VERSION: u8 = 2;
+
+// Identifies the game logic replays were recorded with. Must be increased
+// whenever a change to the rules would make old replays play out differently.
+pub const RULESET: u32 = 5;
+
+const TAG_KEY_RELEASE: u64 = 0;
+const TAG_KEY_PRESS: u64 = 1;
+const TAG_TOUCH_START: u64 = 2;
+const TAG_TOUCH_END: u64 = 3;
+const TAG_TOUCH_CANCEL: u64 = 4;
+const TAG_TOUCH_MOVE: u64 = 5;
+const TAG_PAUSE: u64 = 6;
+const TAG_SET_MODE: u64 = 7;
+const TAG_SET_MODE_GOAL: u64 = 8;
+const TAG_SET_ROTATION_SYSTEM: u64 = 9;
+const TAG_SET_SCORING_SYSTEM: u64 = 10;
+const TAG_SET_PREVIEW_LENGTH: u64 = 11;
+const TAG_SET_LOCK_DELAY: u64 = 12;
+const TAG_SET_GARBAGE: u64 = 13;
+const TAG_SET_HIDDEN_ROWS: u64 = 14;
+const TAG_SET_RANDOMIZER: u64 = 15;
+const TAG_SET_PIECE_SET: u64 = 16;
+const TAG_LOAD_POSITION: u64 = 17;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    // Frame timestamp as passed to `Game_tick`, rounded to milliseconds
+    Tick(f64),
+
+    // Key code, pressed (1) or released (0)
+    Key(i32, i32),
+
+    // Touch id, x, y
+    TouchStart(i32, i32, i32),
+    TouchEnd(i32, i32, i32),
+    TouchCancel(i32, i32, i32),
+    TouchMove(i32, i32, i32),
+
+    Pause,
+
+    // Settings changed through the `Game_set_*` functions, by id
+    SetMode(u32),
+    SetModeGoal(u32),
+    SetRotationSystem(u32),
+    SetScoringSystem(u32),
+    SetPreviewLength(u32),
+
+    // Duration, max resets (negative for step reset)
+    SetLockDelay(f64, i32),
+
+    // Number of holes, messiness
+    SetGarbage(u32, f64),
+
+    SetHiddenRows(u32),
+    SetRandomizer(u32),
+
+    // Definition as accepted by `piece::parse_piece_set`, empty for the
+    // rotation system's pieces
+    SetPieceSet(String),
+
+    // Fumen of a position to start a practice game from
+    LoadPosition(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Replay {
+    pub settings: Vec<u8>,
+    pub position: String,
+    pub start_time: f64,
+    pub events: Vec<Event>,
+}
+
+impl Event {
+    fn encode(&self, writer: &mut encoding::Writer, last_timestamp: &mut f64) {
+        let tag = |writer: &mut encoding::Writer, tag: u64| { writer.varint(tag << 1 | 1) };
+        let touch = |writer: &mut encoding::Writer, id: i32, x: i32, y: i32| {
+            writer.signed(id as i64);
+            writer.signed(x as i64);
+            writer.signed(y as i64);
+        };
+
+        match *self {
+            Event::Tick(timestamp) => {
+                writer.varint(encoding::zigzag((timestamp - *last_timestamp) as i64) << 1);
+                *last_timestamp = timestamp;
+            },
+            Event::Key(key_code, state) => {
+                tag(writer, if state != 0 { TAG_KEY_PRESS } else { TAG_KEY_RELEASE });
+                writer.varint(key_code as u32 as u64);
+            },
+            Event::TouchStart(id, x, y) => { tag(writer, TAG_TOUCH_START); touch(writer, id, x, y) },
+            Event::TouchEnd(id, x, y) => { tag(writer, TAG_TOUCH_END); touch(writer, id, x, y) },
+            Event::TouchCancel(id, x, y) => { tag(writer, TAG_TOUCH_CANCEL); touch(writer, id, x, y) },
+            Event::TouchMove(id, x, y) => { tag(writer, TAG_TOUCH_MOVE); touch(writer, id, x, y) },
+            Event::Pause => tag(writer, TAG_PAUSE),
+            Event::SetMode(id) => { tag(writer, TAG_SET_MODE); writer.varint(id as u64) },
+            Event::SetModeGoal(value) => { tag(writer, TAG_SET_MODE_GOAL); writer.varint(value as u64) },
+            Event::SetRotationSystem(id) => { tag(writer, TAG_SET_ROTATION_SYSTEM); writer.varint(id as u64) },
+            Event::SetScoringSystem(id) => { tag(writer, TAG_SET_SCORING_SYSTEM); writer.varint(id as u64) },
+            Event::SetPreviewLength(length) => { tag(writer, TAG_SET_PREVIEW_LENGTH); writer.varint(length as u64) },
+            Event::SetLockDelay(duration, max_resets) => {
+                tag(writer, TAG_SET_LOCK_DELAY);
+                writer.f64(duration);
+                writer.signed(max_resets as i64);
+            },
+            Event::SetGarbage(num_holes, messiness) => {
+                tag(writer, TAG_SET_GARBAGE);
+                writer.varint(num_holes as u64);
+                writer.f64(messiness);
+            },
+            Event::SetHiddenRows(num_rows) => { tag(writer, TAG_SET_HIDDEN_ROWS); writer.varint(num_rows as u64) },
+            Event::SetRandomizer(id) => { tag(writer, TAG_SET_RANDOMIZER); writer.varint(id as u64) },
+            Event::SetPieceSet(ref text) => { tag(writer, TAG_SET_PIECE_SET); writer.string(text) },
+            Event::LoadPosition(ref text) => { tag(writer, TAG_LOAD_POSITION); writer.string(text) },
+        }
+    }
+
+    fn decode(reader: &mut encoding::Reader, last_timestamp: &mut f64) -> Option<Self> {
+        let header = reader.varint()?;
+        if header & 1 == 0 {
+            *last_timestamp += encoding::unzigzag(header >> 1) as f64;
+            return Some(Event::Tick(*last_timestamp));
+        }
+
+        let int = |reader: &mut encoding::Reader| { reader.signed().map(|x| { x as i32 }) };
+        let uint = |reader: &mut encoding::Reader| { reader.varint().map(|x| { x as u32 }) };
+
+        let event = match header >> 1 {
+            TAG_KEY_RELEASE => Event::Key(uint(reader)? as i32, 0),
+            TAG_KEY_PRESS => Event::Key(uint(reader)? as i32, 1),
+            TAG_TOUCH_START => Event::TouchStart(int(reader)?, int(reader)?, int(reader)?),
+            TAG_TOUCH_END => Event::TouchEnd(int(reader)?, int(reader)?, int(reader)?),
+            TAG_TOUCH_CANCEL => Event::TouchCancel(int(reader)?, int(reader)?, int(reader)?),
+            TAG_TOUCH_MOVE => Event::TouchMove(int(reader)?, int(reader)?, int(reader)?),
+            TAG_PAUSE => Event::Pause,
+            TAG_SET_MODE => Event::SetMode(uint(reader)?),
+            TAG_SET_MODE_GOAL => Event::SetModeGoal(uint(reader)?),
+            TAG_SET_ROTATION_SYSTEM => Event::SetRotationSystem(uint(reader)?),
+            TAG_SET_SCORING_SYSTEM => Event::SetScoringSystem(uint(reader)?),
+            TAG_SET_PREVIEW_LENGTH => Event::SetPreviewLength(uint(reader)?),
+            TAG_SET_LOCK_DELAY => Event::SetLockDelay(reader.f64()?, int(reader)?),
+            TAG_SET_GARBAGE => Event::SetGarbage(uint(reader)?, reader.f64()?),
+            TAG_SET_HIDDEN_ROWS => Event::SetHiddenRows(uint(reader)?),
+            TAG_SET_RANDOMIZER => Event::SetRandomizer(uint(reader)?),
+            TAG_SET_PIECE_SET => Event::SetPieceSet(reader.string()?),
+            TAG_LOAD_POSITION => Event::LoadPosition(reader.string()?),
+            _ => return None,
+        };
+
+        Some(event)
+    }
+}
+
+impl Replay {
+    pub fn new(settings: Vec<u8>, position: &str, start_time: f64) -> Self {
+        Self {
+            settings,
+            position: position.to_string(),
+            start_time,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = encoding::Writer::new();
+        writer.raw(MAGIC);
+        writer.u8(VERSION);
+        writer.varint(RULESET as u64);
+        writer.bytes(&self.settings);
+        writer.string(&self.position);
+        writer.f64(self.start_time);
+
+        let mut last_timestamp = self.start_time;
+        for event in self.events.iter() {
+            event.encode(&mut writer, &mut last_timestamp);
+        }
+
+        writer.into_checksummed_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.starts_with(MAGIC) {
+            return Err("Not a replay".to_string());
+        }
+
+        let data = encoding::verify_checksum(bytes).ok_or("Checksum mismatch, the replay is damaged")?;
+
+        let mut reader = encoding::Reader::new(&data[MAGIC.len()..]);
+        let (version, ruleset) = (|| { Some((reader.u8()?, reader.varint()?)) })().ok_or("Truncated header")?;
+
+        if version != VERSION {
+            return Err(format!("Unsupported replay format version {}", version));
+        }
+
+        if ruleset != RULESET as u64 {
+            return Err(format!("Replay was recorded with ruleset {}, but this is ruleset {}", ruleset, RULESET));
+        }
+
+        let header = (|| { Some((reader.bytes()?.to_vec(), reader.string()?, reader.f64()?)) })();
+        let (settings, position, start_time) = header.ok_or("Truncated header")?;
+
+        let mut replay = Self::new(settings, &position, start_time);
+        let mut last_timestamp = start_time;
+        while !reader.is_empty() {
+            let position = MAGIC.len() + reader.position();
+            match Event::decode(&mut reader, &mut last_timestamp) {
+                Some(event) => replay.push(event),
+                None => return Err(format!("Invalid event at offset {}", position)),
+            }
+        }
+
+        Ok(replay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Replay {
+        let mut replay = Replay::new(vec![1, 2, 3, 255], "v115@vhAAgH", 1000.0);
+        let events = vec![
+            Event::Tick(1016.0),
+            Event::Key(32, 1),
+            Event::Key(32, 0),
+            Event::Tick(1033.0),
+            Event::TouchStart(0, 10, -20),
+            Event::TouchMove(0, 15, -25),
+            Event::TouchEnd(0, 15, -25),
+            Event::TouchCancel(-1, 0, 0),
+            Event::Pause,
+            Event::SetMode(2),
+            Event::SetModeGoal(40),
+            Event::SetRotationSystem(1),
+            Event::SetScoringSystem(2),
+            Event::SetPreviewLength(5),
+            Event::SetLockDelay(500.0, -1),
+            Event::SetGarbage(2, 0.5),
+            Event::SetHiddenRows(3),
+            Event::SetRandomizer(4),
+            Event::SetPieceSet("O #ffff00\n##\n##\n".to_string()),
+            Event::LoadPosition(String::new()),
+
+            // Timestamps may go backwards after a clock change
+            Event::Tick(900.0),
+            Event::Tick(100_000.0),
+        ];
+
+        for event in events {
+            replay.push(event);
+        }
+
+        replay
+    }
+
+    #[test]
+    fn round_trip() {
+        let replay = sample();
+        assert_eq!(Replay::from_bytes(&replay.to_bytes()), Ok(replay));
+
+        let empty = Replay::new(Vec::new(), "", 0.0);
+        assert_eq!(Replay::from_bytes(&empty.to_bytes()), Ok(empty));
+    }
+
+    #[test]
+    fn frames_take_a_byte() {
+        let mut replay = Replay::new(Vec::new(), "", 0.0);
+        let len = replay.to_bytes().len();
+
+        for i in 1..=60 {
+            replay.push(Event::Tick(i as f64 * 16.0));
+        }
+
+        assert_eq!(replay.to_bytes().len(), len + 60);
+    }
+
+    #[test]
+    fn invalid_replays() {
+        let bytes = sample().to_bytes();
+        let rechecksum = |data: &[u8]| {
+            let mut writer = encoding::Writer::new();
+            writer.raw(data);
+            writer.into_checksummed_bytes()
+        };
+
+        assert!(Replay::from_bytes(&[]).is_err());
+        assert!(Replay::from_bytes(b"RSTS\x04").is_err());
+        assert!(Replay::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut damaged = bytes.clone();
+        damaged[10] ^= 1;
+        assert_eq!(Replay::from_bytes(&damaged), Err("Checksum mismatch, the replay is damaged".to_string()));
+
+        let mut data = bytes[..bytes.len() - 4].to_vec();
+        data[4] = VERSION + 1;
+        assert_eq!(Replay::from_bytes(&rechecksum(&data)), Err(format!("Unsupported replay format version {}", VERSION + 1)));
+
+        let mut data = bytes[..bytes.len() - 4].to_vec();
+        data[5] = RULESET as u8 + 1;
+        assert!(Replay::from_bytes(&rechecksum(&data)).unwrap_err().contains("ruleset"));
+
+        // Cut off in the middle of the piece set event
+        let data = &bytes[..bytes.len() - 20];
+        assert!(Replay::from_bytes(&rechecksum(data)).unwrap_err().starts_with("Invalid event"));
+
+        let mut data = bytes[..bytes.len() - 4].to_vec();
+        data.push((TAG_LOAD_POSITION + 1) as u8 * 2 + 1);
+        assert!(Replay::from_bytes(&rechecksum(&data)).unwrap_err().starts_with("Invalid event"));
+    }
+}
@@ -0,0 +1,495 @@
+use crate::{piece, board, util};
+
+pub trait RotationSystem {
+    fn make_pieces(&self) -> Vec<piece::Piece>;
+
+    // Offsets (in board coordinates, i.e. positive y is down) to try in order
+    // when rotating `piece` at `position` from rotation `from` to `to`.
+    fn kicks(&self, board: &board::Board, piece: &piece::Piece, position: &util::Position, from: usize, to: usize) -> Vec<(i32, i32)>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Spin {
+    None,
+    Mini,
+    Full,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Srs,
+    Ars,
+    Nes,
+    Legacy,
+}
+
+pub struct Srs {
+    // Pieces the I and O offsets were made for
+    true_rotation_pieces: Vec<piece::Piece>,
+}
+
+pub struct Ars;
+pub struct Nes;
+pub struct Legacy;
+
+// Offset data for the "true rotation" pieces of `piece::make_ttc_original`,
+// with positive y pointing up. The kick tests for a rotation are the offsets
+// of the current rotation minus those of the new one, which yields the
+// standard JLSTZ and I wall kick tables.
+//
+// See https://tetris.wiki/SRS#How_Guideline_SRS_Really_Works
+const SRS_OFFSETS_JLSTZ: [[(i32, i32); 5]; 4] = [
+    [( 0,  0), ( 0,  0), ( 0,  0), ( 0,  0), ( 0,  0)],
+    [( 0,  0), ( 1,  0), ( 1, -1), ( 0,  2), ( 1,  2)],
+    [( 0,  0), ( 0,  0), ( 0,  0), ( 0,  0), ( 0,  0)],
+    [( 0,  0), (-1,  0), (-1, -1), ( 0,  2), (-1,  2)],
+];
+
+const SRS_OFFSETS_I: [[(i32, i32); 5]; 4] = [
+    [( 0,  0), (-1,  0), ( 2,  0), (-1,  0), ( 2,  0)],
+    [(-1,  0), ( 0,  0), ( 0,  0), ( 0,  1), ( 0, -2)],
+    [(-1,  1), ( 1,  1), (-2,  1), ( 1,  0), (-2,  0)],
+    [( 0,  1), ( 0,  1), ( 0,  1), ( 0, -1), ( 0,  2)],
+];
+
+const SRS_OFFSETS_O: [[(i32, i32); 1]; 4] = [
+    [( 0,  0)],
+    [( 0, -1)],
+    [(-1, -1)],
+    [(-1,  0)],
+];
+
+impl Kind {
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Kind::Srs),
+            1 => Some(Kind::Ars),
+            2 => Some(Kind::Nes),
+            3 => Some(Kind::Legacy),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            Kind::Srs => 0,
+            Kind::Ars => 1,
+            Kind::Nes => 2,
+            Kind::Legacy => 3,
+        }
+    }
+
+    pub fn create(&self) -> Box<dyn RotationSystem> {
+        match self {
+            Kind::Srs => Box::new(Srs::new()),
+            Kind::Ars => Box::new(Ars),
+            Kind::Nes => Box::new(Nes),
+            Kind::Legacy => Box::new(Legacy),
+        }
+    }
+}
+
+fn offset_kicks<const N: usize>(offsets: &[[(i32, i32); N]; 4], from: usize, to: usize) -> Vec<(i32, i32)> {
+    offsets[from % 4].iter()
+        .zip(offsets[to % 4].iter())
+        .map(|(a, b)| { (a.0 - b.0, b.1 - a.1) })
+        .collect()
+}
+
+impl Srs {
+    fn new() -> Self {
+        Self {
+            true_rotation_pieces: piece::make_ttc_original(),
+        }
+    }
+}
+
+impl RotationSystem for Srs {
+    fn make_pieces(&self) -> Vec<piece::Piece> {
+        piece::make_ttc_original()
+    }
+
+    fn kicks(&self, _board: &board::Board, piece: &piece::Piece, _position: &util::Position, from: usize, to: usize) -> Vec<(i32, i32)> {
+        // The I and O offsets only fit the matrices they were made for. Other
+        // pieces of those names, e.g. from custom piece sets, rotate about
+        // the center of their matrix like the rest.
+        let is_true_rotation = || {
+            self.true_rotation_pieces.iter().any(|x| { x.name == piece.name && x.has_same_shape(piece) })
+        };
+
+        match piece.name.as_str() {
+            "I" if is_true_rotation() => offset_kicks(&SRS_OFFSETS_I, from, to),
+            "O" if is_true_rotation() => offset_kicks(&SRS_OFFSETS_O, from, to),
+            _ => offset_kicks(&SRS_OFFSETS_JLSTZ, from, to),
+        }
+    }
+}
+
+impl RotationSystem for Ars {
+    fn make_pieces(&self) -> Vec<piece::Piece> {
+        piece::make_ars()
+    }
+
+    fn kicks(&self, board: &board::Board, piece: &piece::Piece, position: &util::Position, _from: usize, to: usize) -> Vec<(i32, i32)> {
+        match piece.name.as_str() {
+            "I" | "O" => return vec![(0, 0)],
+            "J" | "L" | "T" => {
+                // Center column rule: no kicks if, in reading order, the first
+                // cell obstructing the new rotation is in the middle column.
+                let first_blocked = piece.iter_coords(to).find(|&(bx, by)| {
+                    board.is_blocked(position.x + bx as i32, position.y + by as i32)
+                });
+
+                if let Some((1, _)) = first_blocked {
+                    return vec![(0, 0)];
+                }
+            },
+            _ => {},
+        }
+
+        vec![(0, 0), (1, 0), (-1, 0)]
+    }
+}
+
+impl RotationSystem for Nes {
+    fn make_pieces(&self) -> Vec<piece::Piece> {
+        piece::make_nes()
+    }
+
+    fn kicks(&self, _board: &board::Board, _piece: &piece::Piece, _position: &util::Position, _from: usize, _to: usize) -> Vec<(i32, i32)> {
+        vec![(0, 0)]
+    }
+}
+
+impl RotationSystem for Legacy {
+    fn make_pieces(&self) -> Vec<piece::Piece> {
+        piece::make_standard()
+    }
+
+    fn kicks(&self, _board: &board::Board, _piece: &piece::Piece, _position: &util::Position, _from: usize, _to: usize) -> Vec<(i32, i32)> {
+        [0, 1, 2].iter()
+            .flat_map(|&y| { [0, 1, -1, 2, -2].iter().map(move |&x| { (x, y) }) })
+            .collect()
+    }
+}
+
+// Three-corner T-spin detection. The center and facing of the T are derived
+// from its blocks, so this works for the piece sets of all rotation systems.
+//
+// See https://tetris.wiki/T-Spin
+pub fn detect_t_spin(board: &board::Board, piece: &piece::Piece, position: &util::Position, rotation: usize, kick: (i32, i32)) -> Spin {
+    if piece.name != "T" {
+        return Spin::None;
+    }
+
+    const NEIGHBOURS: [(i32, i32); 4] = [(0, -1), (1, 0), (0, 1), (-1, 0)];
+    const CORNERS: [(i32, i32); 4] = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+
+    let coords: Vec<(i32, i32)> = piece.iter_coords(rotation)
+        .map(|(x, y)| { (x as i32, y as i32) })
+        .collect();
+
+    let center = coords.iter().cloned().find(|&(x, y)| {
+        NEIGHBOURS.iter().filter(|&&(dx, dy)| { coords.contains(&(x + dx, y + dy)) }).count() == 3
+    });
+
+    let (cx, cy) = match center {
+        Some(center) => center,
+        None => return Spin::None,
+    };
+
+    // The T points away from the side of its center that has no block
+    let (fx, fy) = NEIGHBOURS.iter()
+        .find(|&&(dx, dy)| { !coords.contains(&(cx + dx, cy + dy)) })
+        .map(|&(dx, dy)| { (-dx, -dy) })
+        .unwrap();
+
+    let is_occupied = |&&(dx, dy): &&(i32, i32)| {
+        board.is_blocked(position.x + cx + dx, position.y + cy + dy)
+    };
+
+    let num_corners = CORNERS.iter().filter(is_occupied).count();
+    if num_corners < 3 {
+        return Spin::None;
+    }
+
+    let num_front_corners = CORNERS.iter()
+        .filter(|&&(dx, dy)| { (fx != 0 && dx == fx) || (fy != 0 && dy == fy) })
+        .filter(is_occupied)
+        .count();
+
+    // A kick of one column and two rows (e.g. the last SRS test) always
+    // counts as a full T-spin.
+    if num_front_corners == 2 || (kick.0.abs() == 1 && kick.1.abs() == 2) {
+        Spin::Full
+    } else {
+        Spin::Mini
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx;
+
+    // Rotates each piece clockwise four times in open space, taking the first
+    // kick as there's nothing to collide with. Pieces must neither drift nor
+    // jump, i.e. the center of their bounds moves by half a block at most.
+    fn check_rotations(pieces: &[piece::Piece]) {
+        let board = board::Board::new(20, 20, 0);
+        let start = util::Position::new(8, 8);
+
+        // Doubled, so that it stays an integer
+        let center = |piece: &piece::Piece, position: &util::Position, rotation: usize| {
+            let ((x1, y1), (x2, y2)) = piece.bounds(rotation);
+            (2 * position.x + (x1 + x2) as i32, 2 * position.y + (y1 + y2) as i32)
+        };
+
+        for piece in pieces {
+            let mut position = start;
+            for rotation in 0..4 {
+                let kick = Srs::new().kicks(&board, piece, &position, rotation, rotation + 1)[0];
+                let next_position = util::Position::new(position.x + kick.0, position.y + kick.1);
+
+                let (x1, y1) = center(piece, &position, rotation);
+                let (x2, y2) = center(piece, &next_position, (rotation + 1) % 4);
+                assert!((x2 - x1).abs() <= 1 && (y2 - y1).abs() <= 1, "{} moves on rotation {}", piece.name, rotation);
+
+                position = next_position;
+            }
+
+            assert_eq!((position.x, position.y), (start.x, start.y), "{} doesn't return to its start", piece.name);
+        }
+    }
+
+    #[test]
+    fn srs_rotates_in_place() {
+        check_rotations(&piece::make_ttc_original());
+    }
+
+    // Kicks by rotation (0, R, 2, L) before and after
+    type KickTable = [((usize, usize), [(i32, i32); 5]); 8];
+
+    // The wall kick tables from https://tetris.wiki/SRS, with positive y up
+    const WIKI_KICKS_JLSTZ: KickTable = [
+        ((0, 1), [(0, 0), (-1, 0), (-1,  1), (0, -2), (-1, -2)]),
+        ((1, 0), [(0, 0), ( 1, 0), ( 1, -1), (0,  2), ( 1,  2)]),
+        ((1, 2), [(0, 0), ( 1, 0), ( 1, -1), (0,  2), ( 1,  2)]),
+        ((2, 1), [(0, 0), (-1, 0), (-1,  1), (0, -2), (-1, -2)]),
+        ((2, 3), [(0, 0), ( 1, 0), ( 1,  1), (0, -2), ( 1, -2)]),
+        ((3, 2), [(0, 0), (-1, 0), (-1, -1), (0,  2), (-1,  2)]),
+        ((3, 0), [(0, 0), (-1, 0), (-1, -1), (0,  2), (-1,  2)]),
+        ((0, 3), [(0, 0), ( 1, 0), ( 1,  1), (0, -2), ( 1, -2)]),
+    ];
+
+    const WIKI_KICKS_I: KickTable = [
+        ((0, 1), [(0, 0), (-2, 0), ( 1, 0), (-2, -1), ( 1,  2)]),
+        ((1, 0), [(0, 0), ( 2, 0), (-1, 0), ( 2,  1), (-1, -2)]),
+        ((1, 2), [(0, 0), (-1, 0), ( 2, 0), (-1,  2), ( 2, -1)]),
+        ((2, 1), [(0, 0), ( 1, 0), (-2, 0), ( 1, -2), (-2,  1)]),
+        ((2, 3), [(0, 0), ( 2, 0), (-1, 0), ( 2,  1), (-1, -2)]),
+        ((3, 2), [(0, 0), (-2, 0), ( 1, 0), (-2, -1), ( 1,  2)]),
+        ((3, 0), [(0, 0), ( 1, 0), (-2, 0), ( 1, -2), (-2,  1)]),
+        ((0, 3), [(0, 0), (-1, 0), ( 2, 0), (-1,  2), ( 2, -1)]),
+    ];
+
+    #[test]
+    fn srs_kick_tables() {
+        let board = board::Board::new(10, 20, 0);
+        let pieces = piece::make_ttc_original();
+        let position = util::Position::new(3, 3);
+
+        for (name, table) in &[("T", &WIKI_KICKS_JLSTZ), ("I", &WIKI_KICKS_I)] {
+            let piece = pieces.iter().find(|x| { &x.name == name }).unwrap();
+            for &((from, to), expected) in table.iter() {
+                // The true-rotation I moves by a block on the first test,
+                // which the wiki's table already accounts for
+                let kicks = Srs::new().kicks(&board, piece, &position, from, to);
+                let kicks: Vec<(i32, i32)> = kicks.iter().map(|&(x, y)| { (x - kicks[0].0, kicks[0].1 - y) }).collect();
+
+                assert_eq!(kicks, expected.to_vec(), "{} {} -> {}", name, from, to);
+            }
+        }
+
+        // The O never kicks, it only moves to stay in place
+        let piece = pieces.iter().find(|x| { x.name == "O" }).unwrap();
+        for from in 0..4 {
+            assert_eq!(Srs::new().kicks(&board, piece, &position, from, from + 1).len(), 1);
+        }
+    }
+
+    #[test]
+    fn ars_kicks() {
+        let board = board::Board::new(10, 20, 0);
+        let pieces = piece::make_ars();
+        let position = util::Position::new(3, 3);
+        let find = |name: &str| { pieces.iter().find(|x| { x.name == name }).unwrap() };
+
+        assert_eq!(Ars.kicks(&board, find("I"), &position, 0, 1), vec![(0, 0)]);
+        assert_eq!(Ars.kicks(&board, find("O"), &position, 0, 1), vec![(0, 0)]);
+        assert_eq!(Ars.kicks(&board, find("S"), &position, 0, 1), vec![(0, 0), (1, 0), (-1, 0)]);
+
+        // Center column rule: J, L and T don't kick if, in reading order, the
+        // first block in the way is in their middle column
+        let stack = |x: i32, y: i32| {
+            let mut board = board::Board::new(10, 20, 0);
+            let holes: Vec<usize> = (0..10).filter(|&column| { column as i32 != x }).collect();
+            for _ in y..20 {
+                board.insert_garbage_row(&holes, &gfx::Color::gray());
+            }
+
+            board
+        };
+
+        for name in &["J", "L", "T"] {
+            let piece = find(name);
+            for to in 0..4 {
+                let topmost = |column: usize| { piece.iter_coords(to).find(|&(x, _)| { x == column }) };
+
+                let (x, y) = topmost(1).unwrap();
+                let board = stack(position.x + x as i32, position.y + y as i32);
+                assert_eq!(Ars.kicks(&board, piece, &position, 0, to), vec![(0, 0)], "{} {}", name, to);
+
+                for column in &[0, 2] {
+                    if let Some((x, y)) = topmost(*column) {
+                        let board = stack(position.x + x as i32, position.y + y as i32);
+                        assert_eq!(Ars.kicks(&board, piece, &position, 0, to).len(), 3, "{} {}", name, to);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nes_and_legacy_kicks() {
+        let board = board::Board::new(10, 20, 0);
+        let position = util::Position::new(3, 3);
+
+        let piece = &piece::make_nes()[0];
+        assert_eq!(Nes.kicks(&board, piece, &position, 0, 1), vec![(0, 0)]);
+
+        // Sideways first, then up to two rows down
+        let piece = &piece::make_standard()[0];
+        let kicks = Legacy.kicks(&board, piece, &position, 0, 1);
+        assert_eq!(kicks.len(), 15);
+        assert_eq!(kicks[..5], [(0, 0), (1, 0), (-1, 0), (2, 0), (-2, 0)]);
+        assert_eq!(kicks[5], (0, 1));
+    }
+
+    #[test]
+    fn mixed_set_has_srs_i_and_o() {
+        let standard = piece::make_ttc_original();
+        let pieces = piece::parse_piece_set(include_str!("../web/pieces/mixed.txt")).unwrap();
+
+        for name in &["I", "O"] {
+            let find = |pieces: &[piece::Piece]| { pieces.iter().position(|x| { &x.name == name }).unwrap() };
+            assert!(pieces[find(&pieces)].has_same_shape(&standard[find(&standard)]));
+        }
+    }
+
+    #[test]
+    fn piece_sets_rotate_in_place() {
+        for text in &[include_str!("../web/pieces/mixed.txt"), include_str!("../web/pieces/pentomino.txt")] {
+            check_rotations(&piece::parse_piece_set(text).unwrap());
+        }
+    }
+
+    // Board of the given rows, top first, with `X` for blocks
+    fn fixture(rows: &[&str]) -> board::Board {
+        let mut board = board::Board::new(rows[0].len(), rows.len(), 0);
+        for row in rows {
+            let holes: Vec<usize> = row.chars().enumerate().filter(|&(_, c)| { c != 'X' }).map(|(x, _)| { x }).collect();
+            board.insert_garbage_row(&holes, &gfx::Color::gray());
+        }
+
+        board
+    }
+
+    fn spin(rows: &[&str], piece: &piece::Piece, position: (i32, i32), rotation: usize, kick: (i32, i32)) -> Spin {
+        detect_t_spin(&fixture(rows), piece, &util::Position::new(position.0, position.1), rotation, kick)
+    }
+
+    #[test]
+    fn t_spins() {
+        let pieces = piece::make_ttc_original();
+        let t = pieces.iter().find(|x| { x.name == "T" }).unwrap();
+
+        // Pointing down into a slot, with both corners in front of it filled
+        let double = [
+            "..........",
+            "XX........",
+            "X...XXXXXX",
+            "XX.XXXXXXX",
+        ];
+        assert_eq!(spin(&double, t, (1, 1), 2, (0, 0)), Spin::Full);
+
+        // Only one of them filled
+        let mini = [
+            "..........",
+            "XX.X......",
+            "X...XXXXXX",
+            "XX..XXXXXX",
+        ];
+        assert_eq!(spin(&mini, t, (1, 1), 2, (0, 0)), Spin::Mini);
+        assert_eq!(spin(&mini, t, (1, 1), 2, (-1, 0)), Spin::Mini);
+
+        // Unless it got there by a kick of one column and two rows
+        assert_eq!(spin(&mini, t, (1, 1), 2, (1, 2)), Spin::Full);
+        assert_eq!(spin(&mini, t, (1, 1), 2, (-1, -2)), Spin::Full);
+
+        // Two corners aren't enough
+        let open = [
+            "..........",
+            "XX........",
+            "X...XXXXXX",
+            "XX..XXXXXX",
+        ];
+        assert_eq!(spin(&open, t, (1, 1), 2, (0, 0)), Spin::None);
+
+        // Only T pieces spin
+        let s = pieces.iter().find(|x| { x.name == "S" }).unwrap();
+        assert_eq!(spin(&double, s, (1, 1), 2, (0, 0)), Spin::None);
+    }
+
+    #[test]
+    fn zero_line_t_spin() {
+        let pieces = piece::make_ttc_original();
+        let t = pieces.iter().find(|x| { x.name == "T" }).unwrap();
+
+        // Pointing right, wedged between three blocks without filling a row
+        let rows = [
+            "..........",
+            ".....X....",
+            "..........",
+            "...X.X....",
+        ];
+        assert_eq!(spin(&rows, t, (3, 1), 1, (0, 0)), Spin::Full);
+
+        let mut board = fixture(&rows);
+        board.put_piece(t, &util::Position::new(3, 1), 1);
+        assert!(board.clear_lines().is_empty());
+    }
+
+    #[test]
+    fn pentomino_t_spins() {
+        let pieces = piece::parse_piece_set(include_str!("../web/pieces/pentomino.txt")).unwrap();
+        let t = pieces.iter().find(|x| { x.name == "T" }).unwrap();
+
+        // Its center is where the stem meets the bar
+        let rows = [
+            ".X.X......",
+            "..........",
+            ".X.X......",
+            ".X.X......",
+        ];
+        assert_eq!(spin(&rows, t, (1, 1), 0, (0, 0)), Spin::Full);
+
+        let rows = [
+            "..........",
+            "..........",
+            ".X.X......",
+            ".X.X......",
+        ];
+        assert_eq!(spin(&rows, t, (1, 1), 0, (0, 0)), Spin::None);
+    }
+}
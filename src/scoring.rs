@@ -0,0 +1,342 @@
+use crate::{rotation};
+
+pub struct Clear {
+    pub num_lines: usize,
+    pub spin: rotation::Spin,
+    pub combo: u32,
+    pub is_back_to_back: bool,
+}
+
+// Lengths of the current combo and back-to-back chain, `None` while there is none
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Chains {
+    pub combo: Option<u32>,
+    pub back_to_back: Option<u32>,
+}
+
+pub trait ScoringSystem {
+    fn clear_score(&self, clear: &Clear, level: u32) -> u32;
+    fn soft_drop_score(&self, num_rows: u32) -> u32;
+    fn hard_drop_score(&self, num_rows: u32) -> u32;
+
+    fn min_level(&self) -> u32;
+    fn max_level(&self) -> u32;
+    fn level(&self, start_level: u32, num_cleared_lines: u32) -> u32;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Guideline,
+    Nes,
+    Legacy,
+}
+
+pub struct Guideline;
+pub struct Nes;
+pub struct Legacy;
+
+impl Kind {
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            0 => Some(Kind::Guideline),
+            1 => Some(Kind::Nes),
+            2 => Some(Kind::Legacy),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            Kind::Guideline => 0,
+            Kind::Nes => 1,
+            Kind::Legacy => 2,
+        }
+    }
+
+    pub fn create(&self) -> Box<dyn ScoringSystem> {
+        match self {
+            Kind::Guideline => Box::new(Guideline),
+            Kind::Nes => Box::new(Nes),
+            Kind::Legacy => Box::new(Legacy),
+        }
+    }
+}
+
+impl Clear {
+    // Tetrises and T-spins that clear lines, which earn a back-to-back bonus
+    // when done in succession. Zero-line T-spins don't break the chain.
+    pub fn is_difficult(&self) -> bool {
+        self.num_lines >= 4 || (self.num_lines > 0 && self.spin != rotation::Spin::None)
+    }
+}
+
+impl Chains {
+    // Continues or breaks the chains with a placed piece and returns its
+    // clear. Placing a piece without clearing lines breaks the combo, but
+    // only clears that aren't difficult break back-to-back.
+    pub fn add(&mut self, num_lines: usize, spin: rotation::Spin) -> Clear {
+        let mut clear = Clear {
+            num_lines,
+            spin,
+            combo: 0,
+            is_back_to_back: false,
+        };
+
+        let is_difficult = clear.is_difficult();
+        clear.is_back_to_back = is_difficult && self.back_to_back.is_some();
+
+        if num_lines > 0 {
+            self.combo = Some(self.combo.map(|x| { x + 1 }).unwrap_or(0));
+
+            if is_difficult {
+                self.back_to_back = Some(self.back_to_back.map(|x| { x + 1 }).unwrap_or(0));
+            } else {
+                self.back_to_back = None;
+            }
+        } else {
+            self.combo = None;
+        }
+
+        clear.combo = self.combo.unwrap_or(0);
+        clear
+    }
+}
+
+fn t_spin_score(spin: rotation::Spin, num_lines: usize) -> u32 {
+    match (spin, num_lines) {
+        (rotation::Spin::Mini, 0) => 100,
+        (rotation::Spin::Mini, 1) => 200,
+        (rotation::Spin::Mini, _) => 400,
+        (rotation::Spin::Full, 0) => 400,
+        (rotation::Spin::Full, 1) => 800,
+        (rotation::Spin::Full, 2) => 1200,
+        (rotation::Spin::Full, _) => 1600,
+        (rotation::Spin::None, _) => 0,
+    }
+}
+
+fn with_bonuses(clear: &Clear, score: u32, level: u32) -> u32 {
+    let score = if clear.is_back_to_back { score * 3 / 2 } else { score };
+    score + 50 * clear.combo * level
+}
+
+// See https://tetris.wiki/Scoring#Recent_guideline_compatible_games
+impl ScoringSystem for Guideline {
+    fn clear_score(&self, clear: &Clear, level: u32) -> u32 {
+        let score = {
+            if clear.spin != rotation::Spin::None {
+                t_spin_score(clear.spin, clear.num_lines)
+            } else {
+                [0, 100, 300, 500, 800][clear.num_lines.min(4)]
+            }
+        };
+
+        with_bonuses(clear, score * level, level)
+    }
+
+    fn soft_drop_score(&self, num_rows: u32) -> u32 {
+        num_rows
+    }
+
+    fn hard_drop_score(&self, num_rows: u32) -> u32 {
+        2 * num_rows
+    }
+
+    fn min_level(&self) -> u32 {
+        1
+    }
+
+    fn max_level(&self) -> u32 {
+        20
+    }
+
+    fn level(&self, start_level: u32, num_cleared_lines: u32) -> u32 {
+        // Fixed goal of ten lines per level, counted from the start level
+        (start_level + num_cleared_lines / 10).min(self.max_level())
+    }
+}
+
+// See https://tetris.wiki/Scoring#Original_Nintendo_scoring_system
+impl ScoringSystem for Nes {
+    fn clear_score(&self, clear: &Clear, level: u32) -> u32 {
+        [0, 40, 100, 300, 1200][clear.num_lines.min(4)] * (level + 1)
+    }
+
+    fn soft_drop_score(&self, num_rows: u32) -> u32 {
+        num_rows
+    }
+
+    fn hard_drop_score(&self, _num_rows: u32) -> u32 {
+        0
+    }
+
+    fn min_level(&self) -> u32 {
+        0
+    }
+
+    fn max_level(&self) -> u32 {
+        29
+    }
+
+    fn level(&self, start_level: u32, num_cleared_lines: u32) -> u32 {
+        // The first level up happens later when starting on a higher level
+        let first_threshold = (start_level * 10 + 10).min((start_level * 10).saturating_sub(50).max(100));
+
+        let level = {
+            if num_cleared_lines < first_threshold {
+                start_level
+            } else {
+                start_level + 1 + (num_cleared_lines - first_threshold) / 10
+            }
+        };
+
+        level.min(self.max_level())
+    }
+}
+
+// The game's original formula, which only counts cleared lines
+impl ScoringSystem for Legacy {
+    fn clear_score(&self, clear: &Clear, level: u32) -> u32 {
+        if clear.num_lines > 0 {
+            100 * (1 << (clear.num_lines - 1)) + 50 * (level - 1)
+        } else {
+            0
+        }
+    }
+
+    fn soft_drop_score(&self, _num_rows: u32) -> u32 {
+        0
+    }
+
+    fn hard_drop_score(&self, _num_rows: u32) -> u32 {
+        0
+    }
+
+    fn min_level(&self) -> u32 {
+        1
+    }
+
+    fn max_level(&self) -> u32 {
+        20
+    }
+
+    fn level(&self, start_level: u32, num_cleared_lines: u32) -> u32 {
+        (start_level + num_cleared_lines / 10).min(self.max_level())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rotation::Spin;
+
+    fn clear(num_lines: usize, spin: Spin) -> Clear {
+        Clear { num_lines, spin, combo: 0, is_back_to_back: false }
+    }
+
+    fn line_scores(system: &dyn ScoringSystem, level: u32) -> Vec<u32> {
+        (0..=4).map(|x| { system.clear_score(&clear(x, Spin::None), level) }).collect()
+    }
+
+    #[test]
+    fn guideline() {
+        let system = Kind::Guideline.create();
+        assert_eq!(line_scores(&*system, 1), vec![0, 100, 300, 500, 800]);
+        assert_eq!(line_scores(&*system, 3), vec![0, 300, 900, 1500, 2400]);
+
+        let full = |num_lines| { system.clear_score(&clear(num_lines, Spin::Full), 2) };
+        let mini = |num_lines| { system.clear_score(&clear(num_lines, Spin::Mini), 2) };
+        assert_eq!((0..4).map(full).collect::<Vec<_>>(), vec![800, 1600, 2400, 3200]);
+        assert_eq!((0..3).map(mini).collect::<Vec<_>>(), vec![200, 400, 800]);
+
+        // Back-to-back Tetris with a combo of 3 on level 2
+        let tetris = Clear { num_lines: 4, spin: Spin::None, combo: 3, is_back_to_back: true };
+        assert_eq!(system.clear_score(&tetris, 2), 1600 * 3 / 2 + 50 * 3 * 2);
+
+        assert_eq!((system.soft_drop_score(5), system.hard_drop_score(5)), (5, 10));
+
+        assert_eq!(system.level(1, 0), 1);
+        assert_eq!(system.level(1, 9), 1);
+        assert_eq!(system.level(1, 10), 2);
+        assert_eq!(system.level(5, 25), 7);
+        assert_eq!(system.level(15, 1000), system.max_level());
+    }
+
+    #[test]
+    fn nes() {
+        let system = Kind::Nes.create();
+        assert_eq!(line_scores(&*system, 0), vec![0, 40, 100, 300, 1200]);
+        assert_eq!(line_scores(&*system, 9), vec![0, 400, 1000, 3000, 12000]);
+
+        // Spins aren't rewarded
+        assert_eq!(system.clear_score(&clear(2, Spin::Full), 0), 100);
+        assert_eq!((system.soft_drop_score(5), system.hard_drop_score(5)), (5, 0));
+
+        // https://tetris.wiki/Tetris_(NES,_Nintendo)#Level_progression
+        for &(start_level, first_threshold) in &[(0, 10), (5, 60), (9, 100), (10, 100), (15, 100), (16, 110), (19, 140)] {
+            assert_eq!(system.level(start_level, first_threshold - 1), start_level, "start level {}", start_level);
+            assert_eq!(system.level(start_level, first_threshold), start_level + 1, "start level {}", start_level);
+            assert_eq!(system.level(start_level, first_threshold + 10), start_level + 2, "start level {}", start_level);
+        }
+
+        assert_eq!(system.level(19, 10000), system.max_level());
+    }
+
+    #[test]
+    fn legacy() {
+        let system = Kind::Legacy.create();
+        assert_eq!(line_scores(&*system, 1), vec![0, 100, 200, 400, 800]);
+        assert_eq!(line_scores(&*system, 3), vec![0, 200, 300, 500, 900]);
+
+        // Only cleared lines count
+        let tetris = Clear { num_lines: 4, spin: Spin::Full, combo: 5, is_back_to_back: true };
+        assert_eq!(system.clear_score(&tetris, 1), 800);
+        assert_eq!(system.clear_score(&clear(0, Spin::Full), 1), 0);
+        assert_eq!((system.soft_drop_score(5), system.hard_drop_score(5)), (0, 0));
+
+        assert_eq!(system.level(1, 19), 2);
+        assert_eq!(system.level(1, 1000), system.max_level());
+    }
+
+    fn chain(clears: &[(usize, Spin)]) -> (Chains, Vec<(u32, bool)>) {
+        let mut chains = Chains::default();
+        let clears = clears.iter()
+            .map(|&(num_lines, spin)| {
+                let clear = chains.add(num_lines, spin);
+                (clear.combo, clear.is_back_to_back)
+            })
+            .collect();
+
+        (chains, clears)
+    }
+
+    #[test]
+    fn combo() {
+        let (chains, clears) = chain(&[(1, Spin::None), (2, Spin::None), (1, Spin::None)]);
+        assert_eq!(clears, [(0, false), (1, false), (2, false)]);
+        assert_eq!(chains.combo, Some(2));
+
+        let (chains, clears) = chain(&[(1, Spin::None), (0, Spin::None), (1, Spin::None)]);
+        assert_eq!(clears, [(0, false), (0, false), (0, false)]);
+        assert_eq!(chains.combo, Some(0));
+
+        let (chains, _) = chain(&[(1, Spin::None), (0, Spin::Full)]);
+        assert_eq!(chains.combo, None);
+    }
+
+    #[test]
+    fn back_to_back() {
+        let (chains, clears) = chain(&[(4, Spin::None), (2, Spin::Full), (1, Spin::Mini), (4, Spin::None)]);
+        assert_eq!(clears, [(0, false), (1, true), (2, true), (3, true)]);
+        assert_eq!(chains.back_to_back, Some(3));
+
+        let (chains, clears) = chain(&[(4, Spin::None), (3, Spin::None), (4, Spin::None)]);
+        assert_eq!(clears, [(0, false), (1, false), (2, false)]);
+        assert_eq!(chains.back_to_back, Some(0));
+
+        // Pieces that don't clear lines only break the combo
+        let (chains, clears) = chain(&[(4, Spin::None), (0, Spin::None), (0, Spin::Full), (1, Spin::Full)]);
+        assert_eq!(clears, [(0, false), (0, false), (0, false), (0, true)]);
+        assert_eq!(chains, Chains { combo: Some(0), back_to_back: Some(1) });
+    }
+}
@@ -0,0 +1,100 @@
+// Duration of a frame at 60 Hz, the unit of time most games' speed tables
+// are specified in.
+pub const FRAME_DURATION: f64 = 1000.0 / 60.0;
+
+// Gravity (in rows per frame) at which pieces drop to the floor instantly
+pub const INSTANT_GRAVITY: f64 = 20.0;
+
+pub trait SpeedCurve {
+    // Rows per frame the piece falls on its own, i.e. 1.0 is 1G
+    fn gravity(&self, level: u32) -> f64;
+
+    // Delay in milliseconds between locking a piece and spawning the next
+    fn are(&self, level: u32) -> f64;
+    fn line_clear_delay(&self, level: u32) -> f64;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Standard,
+    Master,
+}
+
+pub struct Standard {
+    min_level: u32,
+}
+
+pub struct Master;
+
+// (level, gravity in 1/256 rows per frame) from which on the gravity applies.
+//
+// See https://tetris.wiki/Tetris_The_Absolute_The_Grand_Master_2#Gravity
+const MASTER_GRAVITY: [(u32, u32); 30] = [
+    (  0,    4), ( 30,    6), ( 35,    8), ( 40,   10), ( 50,   12),
+    ( 60,   16), ( 70,   32), ( 80,   48), ( 90,   64), (100,   80),
+    (120,   96), (140,  112), (160,  128), (170,  144), (200,    4),
+    (220,   32), (230,   64), (233,   96), (236,  128), (239,  160),
+    (243,  192), (247,  224), (251,  256), (300,  512), (330,  768),
+    (360, 1024), (400, 1280), (420, 1024), (450,  768), (500, 5120),
+];
+
+// (level, delay in frames), see https://tetris.wiki/ARE
+const MASTER_ARE: [(u32, u32); 3] = [
+    (  0, 25),
+    (700, 16),
+    (800, 12),
+];
+
+const MASTER_LINE_CLEAR_DELAY: [(u32, u32); 5] = [
+    (  0, 40),
+    (500, 25),
+    (600, 16),
+    (700, 12),
+    (800,  6),
+];
+
+impl Kind {
+    pub fn create(&self, min_level: u32) -> Box<dyn SpeedCurve> {
+        match self {
+            Kind::Standard => Box::new(Standard { min_level }),
+            Kind::Master => Box::new(Master),
+        }
+    }
+}
+
+fn lookup<T: Copy>(table: &[(u32, T)], level: u32) -> T {
+    table.iter()
+        .rev()
+        .find(|&&(min_level, _)| { level >= min_level })
+        .map(|&(_, value)| { value })
+        .unwrap_or(table[0].1)
+}
+
+impl SpeedCurve for Standard {
+    fn gravity(&self, level: u32) -> f64 {
+        let period = 0.904974583f64.powf(level.saturating_sub(self.min_level) as f64) * 1000.0;
+        FRAME_DURATION / period
+    }
+
+    fn are(&self, _level: u32) -> f64 {
+        0.0
+    }
+
+    fn line_clear_delay(&self, _level: u32) -> f64 {
+        1000.0
+    }
+}
+
+impl SpeedCurve for Master {
+    fn gravity(&self, level: u32) -> f64 {
+        lookup(&MASTER_GRAVITY, level) as f64 / 256.0
+    }
+
+    fn are(&self, level: u32) -> f64 {
+        lookup(&MASTER_ARE, level) as f64 * FRAME_DURATION
+    }
+
+    fn line_clear_delay(&self, level: u32) -> f64 {
+        lookup(&MASTER_LINE_CLEAR_DELAY, level) as f64 * FRAME_DURATION
+    }
+}
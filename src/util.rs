@@ -1,6 +1,4 @@
-use std::cell::{Cell};
-
-use crate::{js_api};
+use crate::{encoding};
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Position {
@@ -18,8 +16,10 @@ pub struct Clock {
     reference_ts: Option<f64>,
 }
 
-pub struct LinearCongruentialGenerator {
-    seed: Cell<u32>,
+#[derive(Clone, Debug)]
+pub struct Pcg32 {
+    state: u64,
+    increment: u64,
 }
 
 impl Position {
@@ -81,10 +81,14 @@ impl Clock {
     }
 
     pub fn has_passed_multiple_of(&self, divisor: f64, bias: f64) -> bool {
+        self.num_multiples_passed(divisor, bias) > 0
+    }
+
+    pub fn num_multiples_passed(&self, divisor: f64, bias: f64) -> u32 {
         let prev = ((self.elapsed_until(&self.prev_ts) - bias).max(0.0) / divisor).floor();
         let curr = ((self.elapsed_until(&self.curr_ts) - bias).max(0.0) / divisor).floor();
 
-        curr > prev
+        (curr - prev).max(0.0) as u32
     }
 
     pub fn is_suspended(&self) -> bool {
@@ -113,6 +117,20 @@ impl Clock {
         }
     }
 
+    // Only the elapsed time is stored, as timestamps don't carry over to a
+    // new page. Decoded clocks are suspended.
+    pub fn encode(&self, writer: &mut encoding::Writer) {
+        writer.f64(self.elapsed());
+    }
+
+    pub fn decode(reader: &mut encoding::Reader) -> Option<Self> {
+        let mut obj = Self::new();
+        obj.accumulator = Some(reader.f64()?).filter(|x| { x.is_finite() && *x >= 0.0 })?;
+        obj.suspended = true;
+
+        Some(obj)
+    }
+
     pub fn update(&mut self, timestamp: f64) {
         if let None = self.reference_ts {
             self.reference_ts = Some(timestamp);
@@ -123,17 +141,62 @@ impl Clock {
     }
 }
 
-impl LinearCongruentialGenerator {
+impl Pcg32 {
+    // PCG-XSH-RR with 64 bits of state, see https://www.pcg-random.org/
+    const MULTIPLIER: u64 = 6364136223846793005;
+    const DEFAULT_STREAM: u64 = 0xda3e39cb94b95bdb;
+
     pub fn new(seed: u32) -> Self {
-        Self {
-            seed: Cell::new(seed * 12345),
+        let mut obj = Self {
+            state: 0,
+            increment: Self::DEFAULT_STREAM | 1,
+        };
+
+        obj.next_u32();
+        obj.state = obj.state.wrapping_add(seed as u64);
+        obj.next_u32();
+
+        obj
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(Self::MULTIPLIER).wrapping_add(self.increment);
+
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    pub fn next_below(&mut self, bound: u32) -> u32 {
+        assert!(bound > 0);
+
+        // Reject the low end of the range to avoid modulo bias
+        let threshold = bound.wrapping_neg() % bound;
+        loop {
+            let x = self.next_u32();
+            if x >= threshold {
+                return x % bound;
+            }
         }
     }
 
-    pub fn next(&self) -> u32 {
-        let x = self.seed.get() * 1103515245 + 12345;
-        self.seed.set(x);
-        x
+    pub fn next_f64(&mut self) -> f64 {
+        self.next_u32() as f64 / (1u64 << 32) as f64
+    }
+
+    pub fn encode(&self, writer: &mut encoding::Writer) {
+        writer.u64(self.state);
+        writer.u64(self.increment);
+    }
+
+    pub fn decode(reader: &mut encoding::Reader) -> Option<Self> {
+        let state = reader.u64()?;
+
+        // The increment must be odd for the generator to have its full period
+        let increment = Some(reader.u64()?).filter(|x| { x & 1 == 1 })?;
+
+        Some(Self { state, increment })
     }
 }
 
@@ -147,9 +210,9 @@ pub fn clamp<T: PartialOrd>(v: T, min: T, max: T) -> T {
     }
 }
 
-pub fn random_index_pairs(min: usize, max: usize) -> impl Iterator<Item = (usize, usize)> {
+pub fn random_index_pairs(min: usize, max: usize, rng: &mut Pcg32) -> impl Iterator<Item = (usize, usize)> + '_ {
     (1..(max - min)).rev().map(move |i| {
-        let j = (js_api::random() * (i + 1) as f64).floor() as usize;
+        let j = rng.next_below((i + 1) as u32) as usize;
 
         let (ii, jj) = (i + min, j + min);
         assert!(ii >= min && ii < max);
@@ -172,3 +235,48 @@ pub fn format_timestamp(timestamp: f64) -> String {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pcg32_is_seeded() {
+        let numbers = |seed| { let mut rng = Pcg32::new(seed); (0..10).map(|_| { rng.next_u32() }).collect::<Vec<_>>() };
+
+        assert_eq!(numbers(1), numbers(1));
+        assert_ne!(numbers(1), numbers(2));
+        assert_ne!(numbers(0), vec![0; 10]);
+    }
+
+    #[test]
+    fn pcg32_ranges() {
+        let mut rng = Pcg32::new(3);
+        let mut counts = [0; 7];
+        for _ in 0..7000 {
+            counts[rng.next_below(7) as usize] += 1;
+
+            let x = rng.next_f64();
+            assert!((0.0..1.0).contains(&x));
+        }
+
+        assert!(counts.iter().all(|&x| { x > 800 && x < 1200 }), "{:?}", counts);
+        assert_eq!(rng.next_below(1), 0);
+    }
+
+    #[test]
+    fn shuffle() {
+        let mut rng = Pcg32::new(4);
+        let mut values: Vec<usize> = (0..10).collect();
+        for (i, j) in random_index_pairs(2, 8, &mut rng) {
+            values.swap(i, j);
+        }
+
+        assert_eq!(values[..2], [0, 1]);
+        assert_eq!(values[8..], [8, 9]);
+
+        let mut sorted = values.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..10).collect::<Vec<_>>());
+    }
+}
@@ -46,6 +46,16 @@ pub unsafe fn stack_pop_string() -> String {
     String::from_utf8_unchecked(stack_pop_vec())
 }
 
+// Hands the vector over to JS, which is responsible for calling `dealloc`
+pub unsafe fn stack_push_vec(v: Vec<u8>) {
+    let mut v = v.into_boxed_slice();
+    let (address, size) = (v.as_mut_ptr() as u32, v.len() as u32);
+    std::mem::forget(v);
+
+    stack_push(address);
+    stack_push(size);
+}
+
 #[no_mangle]
 pub extern fn alloc(size: u32) -> u32 {
     assert!(size > 0);
@@ -5,8 +5,7 @@ function string_from_rust(instance, address, length) {
     return new TextDecoder().decode(octets);
 }
 
-function string_to_rust(instance, s) {
-    const octets = new TextEncoder("utf-8").encode(s);
+function bytes_to_rust(instance, octets) {
     const address = instance.exports.alloc(octets.length);
 
     let view = new Uint8Array(instance.exports.memory.buffer);
@@ -16,12 +15,51 @@ function string_to_rust(instance, s) {
     instance.exports.stack_push(octets.length);
 }
 
+function string_to_rust(instance, s) {
+    bytes_to_rust(instance, new TextEncoder("utf-8").encode(s));
+}
+
+// Takes ownership of a vector pushed onto the stack by Rust
+function bytes_from_rust_stack(instance) {
+    const length = instance.exports.stack_pop();
+    const address = instance.exports.stack_pop();
+
+    if (length == 0) {
+        return new Uint8Array(0);
+    }
+
+    const octets = new Uint8Array(instance.exports.memory.buffer, address, length).slice();
+    instance.exports.dealloc(address, length);
+
+    return octets;
+}
+
+// URL-safe Base64 without padding, for sharing replays as links and storing
+// saved games
+function base64_encode(octets) {
+    const s = btoa(Array.from(octets, x => String.fromCharCode(x)).join(""));
+    return s.replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function base64_decode(s) {
+    const binary = atob(s.replace(/-/g, "+").replace(/_/g, "/"));
+    return Uint8Array.from(binary, x => x.charCodeAt(0));
+}
+
 function register_object(wasm_module, wasm_instance) {
     const BOARD_WIDTH = 10;
     const BOARD_HEIGHT = 20;
 
     const BLOCK_SIZE_PX = 50;
 
+    const ROTATION_SYSTEMS = { srs: 0, ars: 1, nes: 2, legacy: 3 };
+    const SCORING_SYSTEMS = { guideline: 0, nes: 1, legacy: 2 };
+    const SAVED_GAME_KEY = "rstetris_saved_game";
+
+    const MODES = { endless: 0, sprint: 1, ultra: 2, marathon: 3, zen: 4, dig: 5, survival: 6, master: 7, invisible: 8 };
+    const RANDOMIZERS = { bag: 0, bag14: 1, random: 2, nes: 3, tgm: 4 };
+    const PIECE_SETS = { pentomino: "pieces/pentomino.txt", mixed: "pieces/mixed.txt" };
+
     const BOARD_WIDTH_PX = BLOCK_SIZE_PX * BOARD_WIDTH;
     const BOARD_HEIGHT_PX = BLOCK_SIZE_PX * BOARD_HEIGHT;
 
@@ -40,9 +78,194 @@ function register_object(wasm_module, wasm_instance) {
 
         game_object_address = wasm_instance.exports.Game_new(BOARD_WIDTH, BOARD_HEIGHT);
 
+        const params = new URLSearchParams(window.location.search);
+        if (ROTATION_SYSTEMS.hasOwnProperty(params.get("rotation"))) {
+            wasm_instance.exports.Game_set_rotation_system(game_object_address, ROTATION_SYSTEMS[params.get("rotation")]);
+        }
+
+        if (SCORING_SYSTEMS.hasOwnProperty(params.get("scoring"))) {
+            wasm_instance.exports.Game_set_scoring_system(game_object_address, SCORING_SYSTEMS[params.get("scoring")]);
+        }
+
+        if (MODES.hasOwnProperty(params.get("mode"))) {
+            wasm_instance.exports.Game_set_mode(game_object_address, MODES[params.get("mode")]);
+        }
+
+        if (params.has("goal")) {
+            wasm_instance.exports.Game_set_mode_goal(game_object_address, parseInt(params.get("goal"), 10) || 0);
+        }
+
+        if (RANDOMIZERS.hasOwnProperty(params.get("randomizer"))) {
+            wasm_instance.exports.Game_set_randomizer(game_object_address, RANDOMIZERS[params.get("randomizer")]);
+        }
+
+        if (params.has("preview")) {
+            wasm_instance.exports.Game_set_preview_length(game_object_address, parseInt(params.get("preview"), 10) || 0);
+        }
+
+        if (params.has("lock_delay") || params.has("lock_resets")) {
+            const duration = parseFloat(params.get("lock_delay") || "500");
+            const max_resets = params.get("lock_resets") === "step" ? -1 : parseInt(params.get("lock_resets") || "15", 10);
+            wasm_instance.exports.Game_set_lock_delay(game_object_address, duration, max_resets);
+        }
+
+        if (params.has("hidden_rows")) {
+            wasm_instance.exports.Game_set_hidden_rows(game_object_address, parseInt(params.get("hidden_rows"), 10) || 0);
+        }
+
+        // Loads asynchronously, so the set applies from the first game started after it arrived
+        if (params.has("pieces")) {
+            const name = params.get("pieces");
+            fetch(PIECE_SETS[name] || name)
+                .then(response => {
+                    if (!response.ok) {
+                        throw new Error(response.status + " " + response.statusText);
+                    }
+                    return response.text();
+                })
+                .then(text => {
+                    if (text.length > 0) {
+                        string_to_rust(wasm_instance, text);
+                        wasm_instance.exports.Game_set_piece_set(game_object_address);
+                    }
+                })
+                .catch(error => console.log("Error loading piece set: " + error));
+        }
+
+        if (params.has("garbage_holes") || params.has("garbage_messiness")) {
+            const num_holes = parseInt(params.get("garbage_holes") || "1", 10);
+            const messiness = parseFloat(params.get("garbage_messiness") || "0.3");
+            wasm_instance.exports.Game_set_garbage(game_object_address, num_holes, messiness);
+        }
+
+        const load_replay = octets => {
+            // Rust can't allocate an empty buffer to copy it into
+            if (octets.length == 0) {
+                console.log("Cannot load replay: Empty file");
+                return;
+            }
+
+            bytes_to_rust(wasm_instance, octets);
+            wasm_instance.exports.Game_load_replay(game_object_address);
+        };
+
+        const export_replay = () => {
+            wasm_instance.exports.Game_export_replay(game_object_address);
+            return bytes_from_rust_stack(wasm_instance);
+        };
+
+        const load_position = text => {
+            if (text.length > 0) {
+                string_to_rust(wasm_instance, text);
+                wasm_instance.exports.Game_load_position(game_object_address);
+            }
+        };
+
+        const export_position = () => {
+            wasm_instance.exports.Game_export_position(game_object_address);
+            return new TextDecoder().decode(bytes_from_rust_stack(wasm_instance));
+        };
+
+        // A game in progress is saved when the page is hidden or closed,
+        // and continued when it's opened again.
+        const save_game = () => {
+            wasm_instance.exports.Game_save_state(game_object_address);
+            const octets = bytes_from_rust_stack(wasm_instance);
+
+            if (octets.length > 0) {
+                localStorage.setItem(SAVED_GAME_KEY, base64_encode(octets));
+            } else {
+                localStorage.removeItem(SAVED_GAME_KEY);
+            }
+        };
+
+        if (params.has("replay")) {
+            try {
+                load_replay(base64_decode(params.get("replay")));
+            } catch (error) {
+                console.log("Error loading replay: " + error);
+            }
+        } else if (params.has("fumen")) {
+            // Fumen digits include '+', which query strings turn into spaces
+            load_position(params.get("fumen").replace(/ /g, "+"));
+        } else if (localStorage.getItem(SAVED_GAME_KEY) !== null) {
+            try {
+                bytes_to_rust(wasm_instance, base64_decode(localStorage.getItem(SAVED_GAME_KEY)));
+                wasm_instance.exports.Game_load_state(game_object_address);
+            } catch (error) {
+                console.log("Error loading saved game: " + error);
+            }
+        }
+
+        document.getElementById("save_replay").addEventListener("click", e => {
+            e.preventDefault();
+            const octets = export_replay();
+            if (octets.length == 0) {
+                return;
+            }
+
+            const blob = new Blob([octets], { type: "application/octet-stream" });
+
+            const link = document.createElement("a");
+            link.href = URL.createObjectURL(blob);
+            link.download = "rstetris.replay";
+            link.click();
+            URL.revokeObjectURL(link.href);
+        }, false);
+
+        document.getElementById("copy_replay").addEventListener("click", e => {
+            e.preventDefault();
+            const octets = export_replay();
+            if (octets.length == 0) {
+                return;
+            }
+
+            const url = new URL(window.location.href);
+            url.searchParams.set("replay", base64_encode(octets));
+            navigator.clipboard.writeText(url.toString());
+        }, false);
+
+        document.getElementById("copy_position").addEventListener("click", e => {
+            e.preventDefault();
+            const text = export_position();
+            if (text.length > 0) {
+                navigator.clipboard.writeText(text);
+            }
+        }, false);
+
+        document.getElementById("load_position").addEventListener("click", e => {
+            e.preventDefault();
+            const text = window.prompt("Fumen to practice:");
+            if (text !== null) {
+                load_position(text.trim());
+            }
+        }, false);
+
+        const replay_file = document.getElementById("replay_file");
+        document.getElementById("load_replay").addEventListener("click", e => {
+            e.preventDefault();
+            replay_file.click();
+        }, false);
+
+        replay_file.addEventListener("change", () => {
+            if (replay_file.files.length > 0) {
+                replay_file.files[0].arrayBuffer().then(buffer => load_replay(new Uint8Array(buffer)));
+                replay_file.value = "";
+            }
+        }, false);
+
         window.addEventListener("keydown", e => { wasm_instance.exports.Game_key_handler(game_object_address, e.keyCode, 1); }, false);
         window.addEventListener("keyup", e => { wasm_instance.exports.Game_key_handler(game_object_address, e.keyCode, 0); }, false);
 
+        window.addEventListener("blur", () => { wasm_instance.exports.Game_pause(game_object_address); }, false);
+        document.addEventListener("visibilitychange", () => {
+            if (document.hidden) {
+                wasm_instance.exports.Game_pause(game_object_address);
+                save_game();
+            }
+        }, false);
+        window.addEventListener("pagehide", save_game, false);
+
         function call_touch_handlers(e, handler) {
             e.preventDefault();
             for (let i = 0; i < e.changedTouches.length; i++) {